@@ -0,0 +1,227 @@
+//! Derive macro for the siege-net `Packet` trait
+//!
+//! On a struct, the `packet` attribute describes the message:
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize, Packet)]
+//! #[packet(id = 300, reply_expected, channel = 2, reliability = "reliable")]
+//! pub struct ChatPacket { ... }
+//! ```
+//!
+//! `id` is required, and must never change once the message is in use.  The
+//! rest are optional: `reply_expected` defaults to false, `channel` to 0 and
+//! `reliability` (one of "unreliable", "unreliable_sequenced", "reliable" or
+//! "reliable_ordered") to "unreliable".
+//!
+//! On an enum whose variants each wrap one packet, every method is delegated
+//! to the wrapped packet.
+//!
+//! `#[derive(MessageSet)]` on such an enum lets it be sent in an `Envelope`
+//! alongside siege-net's own control packets.  Each variant goes on the wire as
+//! its packet's id followed by the packet, so the order of the variants does
+//! not matter.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use syn::{Data, DeriveInput, Error, Fields, Ident, LitInt, LitStr, Type};
+
+#[proc_macro_derive(Packet, attributes(packet))]
+pub fn derive_packet(input: TokenStream) -> TokenStream
+{
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    let result = match input.data {
+        Data::Struct(_) => derive_struct(&input),
+        Data::Enum(_) => derive_enum(&input),
+        Data::Union(_) => Err(Error::new_spanned(&input.ident,
+                                                 "Packet cannot be derived for a union")),
+    };
+    match result {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+struct Attributes {
+    id: Option<u16>,
+    reply_expected: bool,
+    channel: u8,
+    reliability: Ident,
+}
+
+fn parse_attributes(input: &DeriveInput) -> syn::Result<Attributes>
+{
+    let mut attributes = Attributes {
+        id: None,
+        reply_expected: false,
+        channel: 0,
+        reliability: Ident::new("Unreliable", Span::call_site()),
+    };
+
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                let id: LitInt = meta.value()?.parse()?;
+                attributes.id = Some(id.base10_parse()?);
+            } else if meta.path.is_ident("reply_expected") {
+                attributes.reply_expected = true;
+            } else if meta.path.is_ident("channel") {
+                let channel: LitInt = meta.value()?.parse()?;
+                attributes.channel = channel.base10_parse()?;
+            } else if meta.path.is_ident("reliability") {
+                let reliability: LitStr = meta.value()?.parse()?;
+                let variant = match &*reliability.value() {
+                    "unreliable" => "Unreliable",
+                    "unreliable_sequenced" => "UnreliableSequenced",
+                    "reliable" => "Reliable",
+                    "reliable_ordered" => "ReliableOrdered",
+                    _ => return Err(Error::new_spanned(&reliability, "unknown reliability")),
+                };
+                attributes.reliability = Ident::new(variant, reliability.span());
+            } else {
+                return Err(meta.error("unknown packet attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(attributes)
+}
+
+fn derive_struct(input: &DeriveInput) -> syn::Result<TokenStream2>
+{
+    let attributes = parse_attributes(input)?;
+    let id = match attributes.id {
+        Some(id) => id,
+        None => return Err(Error::new_spanned(&input.ident,
+                                              "missing #[packet(id = ...)] attribute")),
+    };
+    let reply_expected = attributes.reply_expected;
+    let channel = attributes.channel;
+    let reliability = attributes.reliability;
+
+    let packet = quote!(::siege_net::packets::Packet);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::siege_net::packets::MessageId for #name #ty_generics #where_clause {
+            const MESSAGE_ID: u16 = #id;
+        }
+
+        impl #impl_generics #packet for #name #ty_generics #where_clause {
+            fn reply_expected(&self) -> bool { #reply_expected }
+            fn message_id(&self) -> u16 { #id }
+            fn channel(&self) -> u8 { #channel }
+            fn reliability(&self) -> ::siege_net::packets::Reliability {
+                ::siege_net::packets::Reliability::#reliability
+            }
+        }
+    })
+}
+
+fn derive_enum(input: &DeriveInput) -> syn::Result<TokenStream2>
+{
+    if let Some(attr) = input.attrs.iter().find(|attr| attr.path().is_ident("packet")) {
+        return Err(Error::new_spanned(attr, "an enum takes its packet attributes from \
+                                             the packets it wraps"));
+    }
+    let (names, _) = wrapped_packets(input)?;
+    let names = &names;
+
+    let packet = quote!(::siege_net::packets::Packet);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #packet for #name #ty_generics #where_clause {
+            fn reply_expected(&self) -> bool {
+                match *self { #( #name::#names(ref p) => #packet::reply_expected(p), )* }
+            }
+            fn message_id(&self) -> u16 {
+                match *self { #( #name::#names(ref p) => #packet::message_id(p), )* }
+            }
+            fn channel(&self) -> u8 {
+                match *self { #( #name::#names(ref p) => #packet::channel(p), )* }
+            }
+            fn reliability(&self) -> ::siege_net::packets::Reliability {
+                match *self { #( #name::#names(ref p) => #packet::reliability(p), )* }
+            }
+        }
+    })
+}
+
+#[proc_macro_derive(MessageSet)]
+pub fn derive_message_set(input: TokenStream) -> TokenStream
+{
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match derive_message_set_enum(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn derive_message_set_enum(input: &DeriveInput) -> syn::Result<TokenStream2>
+{
+    let (names, types) = wrapped_packets(input)?;
+    let names = &names;
+    let types = &types;
+
+    let message_id = quote!(::siege_net::packets::MessageId);
+    let private = quote!(::siege_net::__private);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::siege_net::packets::MessageSet for #name #ty_generics #where_clause {
+            const MESSAGE_IDS: &'static [u16] = &[
+                #( <#types as #message_id>::MESSAGE_ID, )*
+            ];
+
+            fn serialize_body<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+                where S: #private::Serializer
+            {
+                match *self {
+                    #( #name::#names(ref p) => #private::Serialize::serialize(p, serializer), )*
+                }
+            }
+
+            fn deserialize_body<'de, D>(id: u16, deserializer: D)
+                                        -> ::std::result::Result<Self, D::Error>
+                where D: #private::Deserializer<'de>
+            {
+                #(
+                    if id == <#types as #message_id>::MESSAGE_ID {
+                        return #private::Deserialize::deserialize(deserializer).map(#name::#names);
+                    }
+                )*
+                Err(<D::Error as #private::DeError>::custom(
+                    format_args!("unknown message id {}", id)))
+            }
+        }
+    })
+}
+
+// The names of the variants of an enum, and the packet types they wrap
+fn wrapped_packets(input: &DeriveInput) -> syn::Result<(Vec<&Ident>, Vec<&Type>)>
+{
+    let variants = match input.data {
+        Data::Enum(ref data) => &data.variants,
+        _ => return Err(Error::new_spanned(&input.ident, "expected an enum")),
+    };
+
+    let mut names = Vec::new();
+    let mut types = Vec::new();
+    for variant in variants {
+        match variant.fields {
+            Fields::Unnamed(ref fields) if fields.unnamed.len() == 1 => {
+                names.push(&variant.ident);
+                types.push(&fields.unnamed[0].ty);
+            },
+            _ => return Err(Error::new_spanned(variant, "each variant must wrap one packet")),
+        }
+    }
+    Ok((names, types))
+}
@@ -0,0 +1,668 @@
+
+use std::fmt::Display;
+use serde::{ser, de, Serialize, Deserialize};
+use serde::de::IntoDeserializer;
+use errors::*;
+use codec::Codec;
+use bits::{BitWriter, BitReader, BIT_FIELD};
+
+/// A compact codec writing packet bodies bit by bit: booleans and `None` take
+/// a single bit, integers wider than a byte are varints (zigzag encoded if
+/// signed), and lengths and enum variants are varints.  Fields written with
+/// `bits::serialize_bits()` take exactly the bits asked for, and `BitEncode`
+/// values (see `bits::serialize()`) are written as they encode themselves.
+///
+/// Like bincode it is not self-describing, so `deserialize_any()` is not
+/// supported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BitCodec;
+
+impl Codec for BitCodec {
+    fn serialized_size<T: Serialize + ?Sized>(value: &T) -> Result<usize>
+    {
+        let mut serializer = BitSerializer { writer: BitWriter::counter(), field_bits: None };
+        value.serialize(&mut serializer)?;
+        Ok(serializer.writer.bytes_written())
+    }
+
+    fn serialize_into<T: Serialize + ?Sized>(value: &T, buf: &mut [u8]) -> Result<usize>
+    {
+        let mut serializer = BitSerializer { writer: BitWriter::new(buf), field_bits: None };
+        value.serialize(&mut serializer)?;
+        Ok(serializer.writer.bytes_written())
+    }
+
+    fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T>
+    {
+        let mut deserializer = BitDeserializer { reader: BitReader::new(bytes), field_bits: None };
+        T::deserialize(&mut deserializer)
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error
+    {
+        msg.to_string().into()
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error
+    {
+        msg.to_string().into()
+    }
+}
+
+struct BitSerializer<'a> {
+    writer: BitWriter<'a>,
+    // Set inside a `serialize_bits()` field, to the width of its integer
+    field_bits: Option<u32>,
+}
+
+impl<'a> BitSerializer<'a> {
+    fn write_unsigned(&mut self, value: u64) -> Result<()>
+    {
+        match self.field_bits.take() {
+            Some(bits) => self.writer.write_bits(value, bits),
+            None => self.writer.write_varint(value),
+        }
+    }
+
+    fn write_len(&mut self, len: Option<usize>) -> Result<()>
+    {
+        match len {
+            Some(len) => self.writer.write_varint(len as u64),
+            None => Err("BitCodec needs the length of sequences up front.".into()),
+        }
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'b mut BitSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<()> { self.writer.write_bool(v) }
+    fn serialize_i8(self, v: i8) -> Result<()> { self.writer.write_bits(v as u8 as u64, 8) }
+    fn serialize_i16(self, v: i16) -> Result<()> { self.writer.write_signed_varint(v as i64) }
+    fn serialize_i32(self, v: i32) -> Result<()> { self.writer.write_signed_varint(v as i64) }
+    fn serialize_i64(self, v: i64) -> Result<()> { self.writer.write_signed_varint(v) }
+
+    fn serialize_u8(self, v: u8) -> Result<()>
+    {
+        match self.field_bits.take() {
+            Some(bits) => self.writer.write_bits(v as u64, bits),
+            None => self.writer.write_bits(v as u64, 8),
+        }
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> { self.write_unsigned(v as u64) }
+    fn serialize_u32(self, v: u32) -> Result<()> { self.write_unsigned(v as u64) }
+    fn serialize_u64(self, v: u64) -> Result<()> { self.write_unsigned(v) }
+    fn serialize_f32(self, v: f32) -> Result<()> { self.writer.write_f32(v) }
+    fn serialize_f64(self, v: f64) -> Result<()> { self.writer.write_f64(v) }
+    fn serialize_char(self, v: char) -> Result<()> { self.writer.write_varint(v as u64) }
+    fn serialize_str(self, v: &str) -> Result<()> { self.serialize_bytes(v.as_bytes()) }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()>
+    {
+        self.writer.write_varint(v.len() as u64)?;
+        self.writer.write_bytes(v)
+    }
+
+    fn serialize_none(self) -> Result<()> { self.writer.write_bool(false) }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()>
+    {
+        self.writer.write_bool(true)?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> { Ok(()) }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> { Ok(()) }
+
+    fn serialize_unit_variant(self, _name: &'static str, index: u32, _variant: &'static str)
+                              -> Result<()>
+    {
+        self.writer.write_varint(index as u64)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T)
+                                                       -> Result<()>
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, index: u32,
+                                                        _variant: &'static str, value: &T)
+                                                        -> Result<()>
+    {
+        self.writer.write_varint(index as u64)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self>
+    {
+        self.write_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self> { Ok(self) }
+
+    fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<Self>
+    {
+        if name == BIT_FIELD { self.field_bits = Some(len as u32); }
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, index: u32, _variant: &'static str,
+                               _len: usize) -> Result<Self>
+    {
+        self.writer.write_varint(index as u64)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self>
+    {
+        self.write_len(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self> { Ok(self) }
+
+    fn serialize_struct_variant(self, _name: &'static str, index: u32, _variant: &'static str,
+                                _len: usize) -> Result<Self>
+    {
+        self.writer.write_varint(index as u64)?;
+        Ok(self)
+    }
+}
+
+// Compound values are just their elements one after another
+macro_rules! serialize_elements {
+    ($($trait_:ident :: $method:ident),*) => {$(
+        impl<'a, 'b> ser::$trait_ for &'b mut BitSerializer<'a> {
+            type Ok = ();
+            type Error = Error;
+
+            fn $method<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()>
+            {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<()> { Ok(()) }
+        }
+    )*}
+}
+serialize_elements!(SerializeSeq::serialize_element, SerializeTuple::serialize_element,
+                    SerializeTupleStruct::serialize_field,
+                    SerializeTupleVariant::serialize_field);
+
+impl<'a, 'b> ser::SerializeMap for &'b mut BitSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()>
+    {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()>
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> { Ok(()) }
+}
+
+impl<'a, 'b> ser::SerializeStruct for &'b mut BitSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _key: &'static str, value: &T)
+                                              -> Result<()>
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> { Ok(()) }
+}
+
+impl<'a, 'b> ser::SerializeStructVariant for &'b mut BitSerializer<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _key: &'static str, value: &T)
+                                              -> Result<()>
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> { Ok(()) }
+}
+
+struct BitDeserializer<'de> {
+    reader: BitReader<'de>,
+    // Set inside a `serialize_bits()` field, to the width of its integer
+    field_bits: Option<u32>,
+}
+
+impl<'de> BitDeserializer<'de> {
+    fn read_unsigned(&mut self, max: u64) -> Result<u64>
+    {
+        let value = match self.field_bits.take() {
+            Some(bits) => self.reader.read_bits(bits)?,
+            None => self.reader.read_varint()?,
+        };
+        if value > max { return Err(ErrorKind::InvalidPacket.into()); }
+        Ok(value)
+    }
+
+    fn read_signed(&mut self, min: i64, max: i64) -> Result<i64>
+    {
+        let value = self.reader.read_signed_varint()?;
+        if value < min || value > max { return Err(ErrorKind::InvalidPacket.into()); }
+        Ok(value)
+    }
+
+    fn read_len(&mut self) -> Result<usize>
+    {
+        Ok(self.read_unsigned(u32::MAX as u64)? as usize)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut BitDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value>
+    {
+        Err("BitCodec cannot deserialize values of unknown type.".into())
+    }
+
+    fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_bool(self.reader.read_bool()?)
+    }
+
+    fn deserialize_i8<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_i8(self.reader.read_bits(8)? as u8 as i8)
+    }
+
+    fn deserialize_i16<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_i16(self.read_signed(i16::MIN as i64, i16::MAX as i64)? as i16)
+    }
+
+    fn deserialize_i32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_i32(self.read_signed(i32::MIN as i64, i32::MAX as i64)? as i32)
+    }
+
+    fn deserialize_i64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_i64(self.reader.read_signed_varint()?)
+    }
+
+    fn deserialize_u8<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        let bits = self.field_bits.take().unwrap_or(8);
+        visitor.visit_u8(self.reader.read_bits(bits)? as u8)
+    }
+
+    fn deserialize_u16<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_u16(self.read_unsigned(u16::MAX as u64)? as u16)
+    }
+
+    fn deserialize_u32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_u32(self.read_unsigned(u32::MAX as u64)? as u32)
+    }
+
+    fn deserialize_u64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_u64(self.read_unsigned(u64::MAX)?)
+    }
+
+    fn deserialize_f32<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_f32(self.reader.read_f32()?)
+    }
+
+    fn deserialize_f64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_f64(self.reader.read_f64()?)
+    }
+
+    fn deserialize_char<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        let value = self.read_unsigned(u32::MAX as u64)? as u32;
+        match ::std::char::from_u32(value) {
+            Some(c) => visitor.visit_char(c),
+            None => Err(ErrorKind::InvalidPacket.into()),
+        }
+    }
+
+    fn deserialize_str<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        self.deserialize_bytes(StrVisitor(visitor))
+    }
+
+    fn deserialize_string<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        self.deserialize_bytes(StrVisitor(visitor))
+    }
+
+    fn deserialize_bytes<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        let len = self.read_len()?;
+        // Borrow the bytes if they start on a byte boundary
+        if let Some(bytes) = self.reader.remaining_bytes() {
+            if bytes.len() < len { return Err(ErrorKind::InvalidPacket.into()); }
+            self.reader.skip_bytes(len)?;
+            return visitor.visit_borrowed_bytes(&bytes[..len]);
+        }
+        if len > self.reader.remaining_bits() / 8 { return Err(ErrorKind::InvalidPacket.into()); }
+        let mut bytes = vec![0; len];
+        self.reader.read_bytes(&mut bytes)?;
+        visitor.visit_byte_buf(bytes)
+    }
+
+    fn deserialize_byte_buf<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        if self.reader.read_bool()? { visitor.visit_some(self) } else { visitor.visit_none() }
+    }
+
+    fn deserialize_unit<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V)
+                                                    -> Result<V::Value>
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V)
+                                                       -> Result<V::Value>
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        let len = self.read_len()?;
+        visitor.visit_seq(Elements { de: self, len: len })
+    }
+
+    fn deserialize_tuple<V: de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_seq(Elements { de: self, len: len })
+    }
+
+    fn deserialize_tuple_struct<V: de::Visitor<'de>>(self, name: &'static str, len: usize,
+                                                     visitor: V) -> Result<V::Value>
+    {
+        if name == BIT_FIELD { self.field_bits = Some(len as u32); }
+        visitor.visit_seq(Elements { de: self, len: len })
+    }
+
+    fn deserialize_map<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value>
+    {
+        let len = self.read_len()?;
+        visitor.visit_map(Elements { de: self, len: len })
+    }
+
+    fn deserialize_struct<V: de::Visitor<'de>>(self, _name: &'static str,
+                                               fields: &'static [&'static str], visitor: V)
+                                               -> Result<V::Value>
+    {
+        visitor.visit_seq(Elements { de: self, len: fields.len() })
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(self, _name: &'static str,
+                                             _variants: &'static [&'static str], visitor: V)
+                                             -> Result<V::Value>
+    {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value>
+    {
+        Err("BitCodec does not write identifiers.".into())
+    }
+
+    fn deserialize_ignored_any<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value>
+    {
+        Err("BitCodec cannot skip values of unknown type.".into())
+    }
+
+    fn is_human_readable(&self) -> bool
+    {
+        false
+    }
+}
+
+// Turns the bytes of a string into a `str` for the visitor
+struct StrVisitor<V>(V);
+
+impl<'de, V: de::Visitor<'de>> de::Visitor<'de> for StrVisitor<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+    {
+        self.0.expecting(f)
+    }
+
+    fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> ::std::result::Result<V::Value, E>
+    {
+        match ::std::str::from_utf8(v) {
+            Ok(s) => self.0.visit_borrowed_str(s),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Bytes(v), &self)),
+        }
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> ::std::result::Result<V::Value, E>
+    {
+        match String::from_utf8(v) {
+            Ok(s) => self.0.visit_string(s),
+            Err(e) => Err(E::invalid_value(de::Unexpected::Bytes(e.as_bytes()), &self)),
+        }
+    }
+}
+
+// The elements of a sequence, tuple, struct or map
+struct Elements<'b, 'de: 'b> {
+    de: &'b mut BitDeserializer<'de>,
+    len: usize,
+}
+
+impl<'b, 'de> de::SeqAccess<'de> for Elements<'b, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T)
+                                                      -> Result<Option<T::Value>>
+    {
+        if self.len == 0 { return Ok(None); }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize>
+    {
+        Some(self.len)
+    }
+}
+
+impl<'b, 'de> de::MapAccess<'de> for Elements<'b, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>>
+    {
+        if self.len == 0 { return Ok(None); }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value>
+    {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize>
+    {
+        Some(self.len)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut BitDeserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)>
+    {
+        let index = self.read_unsigned(u32::MAX as u64)? as u32;
+        let value = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(index))?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut BitDeserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()>
+    {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value>
+    {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value>
+    {
+        visitor.visit_seq(Elements { de: self, len: len })
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(self, fields: &'static [&'static str], visitor: V)
+                                           -> Result<V::Value>
+    {
+        visitor.visit_seq(Elements { de: self, len: fields.len() })
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::net::SocketAddr;
+    use std::collections::BTreeMap;
+    use ring::rand::SystemRandom;
+    use remote::Remote;
+    use router::Router;
+    use codec::BincodeCodec;
+    use bits::{serialize_bits, deserialize_bits};
+    use packets::{Packet, Envelope, ControlPacket, HeartbeatPacket, MessageSet};
+
+    // 12 bits is enough for an entity id
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    struct EntityId(u16);
+    impl Serialize for EntityId {
+        fn serialize<S: ser::Serializer>(&self, s: S) -> ::std::result::Result<S::Ok, S::Error>
+        {
+            serialize_bits(self.0 as u64, 12, s)
+        }
+    }
+    impl<'de> Deserialize<'de> for EntityId {
+        fn deserialize<D: de::Deserializer<'de>>(d: D) -> ::std::result::Result<EntityId, D::Error>
+        {
+            deserialize_bits(12, d).map(|id| EntityId(id as u16))
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    enum Stance { Standing, Crouching, Prone }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    #[packet(id = 300)]
+    struct MovePacket {
+        entity: EntityId,
+        running: bool,
+        jumping: bool,
+        stance: Stance,
+        dx: i32,
+        dy: i32,
+        target: Option<EntityId>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    #[packet(id = 301)]
+    struct ChatPacket<'a> {
+        text: &'a str,
+        scores: BTreeMap<String, u32>,
+        tag: char,
+    }
+
+    let m = MovePacket {
+        entity: EntityId(4000),
+        running: true,
+        jumping: false,
+        stance: Stance::Crouching,
+        dx: -3,
+        dy: 40,
+        target: None,
+    };
+    let size = BitCodec::serialized_size(&m).unwrap();
+    assert_eq!(size, 5); // 12 + 2 + 8 + 8 + 8 + 1 bits
+    assert!(size < BincodeCodec::serialized_size(&m).unwrap());
+    let mut buf = [0; 64];
+    assert_eq!(BitCodec::serialize_into(&m, &mut buf).unwrap(), size);
+    assert_eq!(BitCodec::deserialize::<MovePacket>(&buf[..size]).unwrap(), m);
+
+    let mut scores = BTreeMap::new();
+    scores.insert("red".to_owned(), 3);
+    scores.insert("blue".to_owned(), 100000);
+    let chat = ChatPacket { text: "gg", scores: scores, tag: 'é' };
+    let size = BitCodec::serialize_into(&chat, &mut buf).unwrap();
+    assert_eq!(BitCodec::deserialize::<ChatPacket>(&buf[..size]).unwrap(), chat);
+    assert!(BitCodec::deserialize::<ChatPacket>(&buf[..size - 1]).is_err());
+
+    // Whole packets, with control packets alongside
+    #[derive(Debug, PartialEq, Clone, Packet, MessageSet)]
+    enum Game {
+        Move(MovePacket),
+    }
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut client: Remote<BitCodec> = Remote::with_codec(addr, rng.clone()).unwrap();
+    let mut server: Remote<BitCodec> = Remote::with_codec(addr, rng).unwrap();
+    let envelope: Envelope<Game> = Envelope::Message(Game::Move(m.clone()));
+    let mut bytes = client.serialize_packet(&envelope, 0xABCDE000, 1).unwrap();
+    let (received, _) = server.deserialize_packet::<Envelope<Game>>(&mut bytes[..]).unwrap();
+    assert_eq!(received, envelope);
+
+    let mut router: Router<BitCodec> = Router::default();
+    router.on(move |_, received: MovePacket, _| {
+        assert_eq!(received, m);
+        Ok(())
+    }).unwrap();
+    let mut bytes = client.serialize_packet(&envelope, 0xABCDE000, 1).unwrap();
+    router.dispatch(&mut server, &mut bytes[..]).unwrap();
+    let heartbeat: Envelope<Game> = Envelope::Control(ControlPacket::Heartbeat(HeartbeatPacket));
+    let mut bytes = client.serialize_packet(&heartbeat, 0xABCDE000, 1).unwrap();
+    router.dispatch(&mut server, &mut bytes[..]).unwrap();
+}
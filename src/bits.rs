@@ -0,0 +1,556 @@
+
+use std::fmt;
+use serde::{Serialize, Serializer, Deserializer};
+use serde::ser::{self, SerializeTuple, SerializeTupleStruct};
+use serde::de::{self, DeserializeSeed, SeqAccess, Visitor};
+use errors::*;
+use packets::MAX_PROTO_PACKET;
+use quantize::{quantize, dequantize};
+
+/// Writes values into a buffer bit by bit, least significant bit first.  The
+/// last byte is padded with zero bits.
+pub struct BitWriter<'a> {
+    buf: &'a mut [u8],
+    bits: usize,
+    counting: bool,
+}
+
+impl<'a> BitWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> BitWriter<'a>
+    {
+        BitWriter {
+            buf: buf,
+            bits: 0,
+            counting: false,
+        }
+    }
+
+    /// A writer which writes nothing, only counting how much would be written
+    pub fn counter() -> BitWriter<'static>
+    {
+        BitWriter {
+            buf: &mut [],
+            bits: 0,
+            counting: true,
+        }
+    }
+
+    pub fn bits_written(&self) -> usize
+    {
+        self.bits
+    }
+
+    /// The number of bytes written, counting a partly written last byte
+    pub fn bytes_written(&self) -> usize
+    {
+        self.bits.div_ceil(8)
+    }
+
+    /// Write the low `bits` bits of `value` (up to 64)
+    pub fn write_bits(&mut self, value: u64, bits: u32) -> Result<()>
+    {
+        assert!(bits <= 64);
+        let needed = self.bits + bits as usize;
+        if !self.counting && needed > self.buf.len() * 8 {
+            return Err(ErrorKind::BufferTooSmall(needed.div_ceil(8)).into());
+        }
+        if self.counting {
+            self.bits = needed;
+            return Ok(());
+        }
+
+        let mut value = if bits == 64 { value } else { value & ((1 << bits) - 1) };
+        let mut remaining = bits;
+        while remaining > 0 {
+            let byte = self.bits / 8;
+            let offset = (self.bits % 8) as u32;
+            let n = ::std::cmp::min(8 - offset, remaining);
+            if offset == 0 { self.buf[byte] = 0; }
+            self.buf[byte] |= ((value & ((1 << n) - 1)) as u8) << offset;
+            value >>= n;
+            remaining -= n;
+            self.bits += n as usize;
+        }
+        Ok(())
+    }
+
+    pub fn write_bool(&mut self, value: bool) -> Result<()>
+    {
+        self.write_bits(value as u64, 1)
+    }
+
+    /// Write `value`, which must be within `min..=max`, in just enough bits for
+    /// that range
+    pub fn write_ranged(&mut self, value: i64, min: i64, max: i64) -> Result<()>
+    {
+        if value < min || value > max {
+            return Err(format!("Value {} is outside {}..={}", value, min, max).into());
+        }
+        self.write_bits(value.wrapping_sub(min) as u64, bits_for_range(min, max))
+    }
+
+    /// Write `value` in groups of 7 bits, each followed by a bit saying whether
+    /// another group follows.  Small values take a byte.
+    pub fn write_varint(&mut self, mut value: u64) -> Result<()>
+    {
+        loop {
+            let group = value & 0x7F;
+            value >>= 7;
+            self.write_bits(group, 7)?;
+            self.write_bool(value != 0)?;
+            if value == 0 { return Ok(()); }
+        }
+    }
+
+    /// Write a varint, zigzag encoded so that small negative values are small
+    pub fn write_signed_varint(&mut self, value: i64) -> Result<()>
+    {
+        self.write_varint(((value << 1) ^ (value >> 63)) as u64)
+    }
+
+    pub fn write_f32(&mut self, value: f32) -> Result<()>
+    {
+        self.write_bits(value.to_bits() as u64, 32)
+    }
+
+    pub fn write_f64(&mut self, value: f64) -> Result<()>
+    {
+        self.write_bits(value.to_bits(), 64)
+    }
+
+    /// Write `value` clamped to `min..=max`, as one of the `2^bits` evenly
+    /// spaced steps across that range
+    pub fn write_quantized(&mut self, value: f32, min: f32, max: f32, bits: u32) -> Result<()>
+    {
+        self.write_bits(quantize(value, min, max, bits), bits)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()>
+    {
+        for byte in bytes {
+            self.write_bits(*byte as u64, 8)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads values written by a `BitWriter`
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    bits: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> BitReader<'a>
+    {
+        BitReader {
+            bytes: bytes,
+            bits: 0,
+        }
+    }
+
+    pub fn bits_read(&self) -> usize
+    {
+        self.bits
+    }
+
+    pub fn remaining_bits(&self) -> usize
+    {
+        self.bytes.len() * 8 - self.bits
+    }
+
+    /// Whether the next value starts at a byte boundary
+    pub fn is_aligned(&self) -> bool
+    {
+        self.bits & 7 == 0
+    }
+
+    /// The bytes after the current position, if it is at a byte boundary
+    pub fn remaining_bytes(&self) -> Option<&'a [u8]>
+    {
+        if self.is_aligned() { Some(&self.bytes[self.bits / 8..]) } else { None }
+    }
+
+    /// Skip `len` bytes, which must be at a byte boundary
+    pub fn skip_bytes(&mut self, len: usize) -> Result<()>
+    {
+        if !self.is_aligned() || self.bits / 8 + len > self.bytes.len() {
+            return Err(ErrorKind::InvalidPacket.into());
+        }
+        self.bits += len * 8;
+        Ok(())
+    }
+
+    pub fn read_bits(&mut self, bits: u32) -> Result<u64>
+    {
+        assert!(bits <= 64);
+        if self.bits + bits as usize > self.bytes.len() * 8 {
+            return Err(ErrorKind::InvalidPacket.into());
+        }
+
+        let mut value: u64 = 0;
+        let mut done = 0;
+        while done < bits {
+            let byte = self.bytes[self.bits / 8] as u64;
+            let offset = (self.bits % 8) as u32;
+            let n = ::std::cmp::min(8 - offset, bits - done);
+            value |= ((byte >> offset) & ((1 << n) - 1)) << done;
+            done += n;
+            self.bits += n as usize;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool>
+    {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    pub fn read_ranged(&mut self, min: i64, max: i64) -> Result<i64>
+    {
+        let value = min.wrapping_add(self.read_bits(bits_for_range(min, max))? as i64);
+        if value < min || value > max {
+            return Err(ErrorKind::InvalidPacket.into());
+        }
+        Ok(value)
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64>
+    {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let group = self.read_bits(7)?;
+            if shift > 63 || (shift == 63 && group > 1) {
+                return Err(ErrorKind::InvalidPacket.into());
+            }
+            value |= group << shift;
+            shift += 7;
+            if !self.read_bool()? { return Ok(value); }
+        }
+    }
+
+    pub fn read_signed_varint(&mut self) -> Result<i64>
+    {
+        let value = self.read_varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32>
+    {
+        Ok(f32::from_bits(self.read_bits(32)? as u32))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64>
+    {
+        Ok(f64::from_bits(self.read_bits(64)?))
+    }
+
+    pub fn read_quantized(&mut self, min: f32, max: f32, bits: u32) -> Result<f32>
+    {
+        Ok(dequantize(self.read_bits(bits)?, min, max, bits))
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()>
+    {
+        for byte in bytes.iter_mut() {
+            *byte = self.read_bits(8)? as u8;
+        }
+        Ok(())
+    }
+}
+
+// The number of bits needed for any value in `min..=max`
+fn bits_for_range(min: i64, max: i64) -> u32
+{
+    assert!(min <= max);
+    64 - (max.wrapping_sub(min) as u64).leading_zeros()
+}
+
+/// A value which writes itself to a bit stream.  A message which implements
+/// this can be sent with any codec by having its `Serialize` and `Deserialize`
+/// implementations call `bits::serialize()` and `bits::deserialize()`; with
+/// `BitCodec` its bits go straight into the packet body.
+pub trait BitEncode: Sized {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()>;
+    fn decode(reader: &mut BitReader) -> Result<Self>;
+}
+
+impl BitEncode for bool {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()> { writer.write_bool(*self) }
+    fn decode(reader: &mut BitReader) -> Result<bool> { reader.read_bool() }
+}
+
+impl BitEncode for u8 {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()> { writer.write_bits(*self as u64, 8) }
+    fn decode(reader: &mut BitReader) -> Result<u8> { Ok(reader.read_bits(8)? as u8) }
+}
+
+macro_rules! bit_encode_varint {
+    ($($ty:ty),*) => {$(
+        impl BitEncode for $ty {
+            fn encode(&self, writer: &mut BitWriter) -> Result<()>
+            {
+                writer.write_varint(*self as u64)
+            }
+            fn decode(reader: &mut BitReader) -> Result<$ty>
+            {
+                let value = reader.read_varint()?;
+                if value > <$ty>::MAX as u64 { return Err(ErrorKind::InvalidPacket.into()); }
+                Ok(value as $ty)
+            }
+        }
+    )*}
+}
+bit_encode_varint!(u16, u32, u64);
+
+macro_rules! bit_encode_signed_varint {
+    ($($ty:ty),*) => {$(
+        impl BitEncode for $ty {
+            fn encode(&self, writer: &mut BitWriter) -> Result<()>
+            {
+                writer.write_signed_varint(*self as i64)
+            }
+            fn decode(reader: &mut BitReader) -> Result<$ty>
+            {
+                let value = reader.read_signed_varint()?;
+                if value < <$ty>::MIN as i64 || value > <$ty>::MAX as i64 {
+                    return Err(ErrorKind::InvalidPacket.into());
+                }
+                Ok(value as $ty)
+            }
+        }
+    )*}
+}
+bit_encode_signed_varint!(i16, i32, i64);
+
+impl BitEncode for f32 {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()> { writer.write_f32(*self) }
+    fn decode(reader: &mut BitReader) -> Result<f32> { reader.read_f32() }
+}
+
+impl BitEncode for f64 {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()> { writer.write_f64(*self) }
+    fn decode(reader: &mut BitReader) -> Result<f64> { reader.read_f64() }
+}
+
+// The name of the tuple struct `serialize_bits()` wraps a bit field in.
+// `BitCodec` writes the field in exactly as many bits as the struct's length.
+pub(crate) const BIT_FIELD: &str = "siege_net::BitField";
+
+/// Serialize the low `bits` bits of `value`.  `BitCodec` writes exactly that
+/// many bits; other codecs write the smallest unsigned integer which fits.
+/// Read it back with `deserialize_bits()`.
+pub fn serialize_bits<S: Serializer>(value: u64, bits: u32, serializer: S)
+                                     -> ::std::result::Result<S::Ok, S::Error>
+{
+    let mut field = serializer.serialize_tuple_struct(BIT_FIELD, bits as usize)?;
+    match bits {
+        0..=8 => field.serialize_field(&(value as u8))?,
+        9..=16 => field.serialize_field(&(value as u16))?,
+        17..=32 => field.serialize_field(&(value as u32))?,
+        _ => field.serialize_field(&value)?,
+    }
+    field.end()
+}
+
+/// Deserialize a field written by `serialize_bits()`
+pub fn deserialize_bits<'de, D: Deserializer<'de>>(bits: u32, deserializer: D)
+                                                   -> ::std::result::Result<u64, D::Error>
+{
+    deserializer.deserialize_tuple_struct(BIT_FIELD, bits as usize, BitFieldVisitor(bits))
+}
+
+struct BitFieldVisitor(u32);
+
+impl<'de> Visitor<'de> for BitFieldVisitor {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "a {} bit field", self.0)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> ::std::result::Result<u64, A::Error>
+    {
+        let value = match self.0 {
+            0..=8 => seq.next_element::<u8>()?.map(|v| v as u64),
+            9..=16 => seq.next_element::<u16>()?.map(|v| v as u64),
+            17..=32 => seq.next_element::<u32>()?.map(|v| v as u64),
+            _ => seq.next_element::<u64>()?,
+        };
+        value.ok_or_else(|| de::Error::invalid_length(0, &self))
+    }
+}
+
+// A `serialize_bits()` field as a value
+struct BitField(u64, u32);
+
+impl Serialize for BitField {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    {
+        serialize_bits(self.0, self.1, serializer)
+    }
+}
+
+/// Serialize a `BitEncode` value: the number of bits it encodes to, then the
+/// bits themselves
+pub fn serialize<T: BitEncode, S: Serializer>(value: &T, serializer: S)
+                                              -> ::std::result::Result<S::Ok, S::Error>
+{
+    let mut buf = [0_u8; MAX_PROTO_PACKET];
+    let bits = {
+        let mut writer = BitWriter::new(&mut buf);
+        value.encode(&mut writer).map_err(ser::Error::custom)?;
+        writer.bits_written()
+    };
+
+    let mut reader = BitReader::new(&buf);
+    let chunks = bits.div_ceil(64);
+    let mut tuple = serializer.serialize_tuple(1 + chunks)?;
+    tuple.serialize_element(&(bits as u32))?;
+    for i in 0..chunks {
+        let width = ::std::cmp::min(64, bits - i * 64) as u32;
+        let chunk = reader.read_bits(width).map_err(ser::Error::custom)?;
+        tuple.serialize_element(&BitField(chunk, width))?;
+    }
+    tuple.end()
+}
+
+/// Deserialize a `BitEncode` value written by `serialize()`
+pub fn deserialize<'de, T: BitEncode, D: Deserializer<'de>>(deserializer: D)
+                                                            -> ::std::result::Result<T, D::Error>
+{
+    // The length is not known up front, but a tuple only has to be long enough
+    deserializer.deserialize_tuple(usize::MAX, EncodedVisitor(::std::marker::PhantomData))
+}
+
+struct EncodedVisitor<T>(::std::marker::PhantomData<T>);
+
+impl<'de, T: BitEncode> Visitor<'de> for EncodedVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str("a bit count followed by the bits")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> ::std::result::Result<T, A::Error>
+    {
+        let bits = match seq.next_element::<u32>()? {
+            Some(bits) => bits as usize,
+            None => return Err(de::Error::invalid_length(0, &self)),
+        };
+        if bits > MAX_PROTO_PACKET * 8 {
+            return Err(de::Error::custom("bit count too large"));
+        }
+
+        let mut buf = [0_u8; MAX_PROTO_PACKET];
+        {
+            let mut writer = BitWriter::new(&mut buf);
+            for i in 0..bits.div_ceil(64) {
+                let width = ::std::cmp::min(64, bits - i * 64) as u32;
+                let chunk = match seq.next_element_seed(BitFieldSeed(width))? {
+                    Some(chunk) => chunk,
+                    None => return Err(de::Error::invalid_length(1 + i, &self)),
+                };
+                writer.write_bits(chunk, width).map_err(de::Error::custom)?;
+            }
+        }
+        T::decode(&mut BitReader::new(&buf[..bits.div_ceil(8)])).map_err(de::Error::custom)
+    }
+}
+
+struct BitFieldSeed(u32);
+
+impl<'de> DeserializeSeed<'de> for BitFieldSeed {
+    type Value = u64;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D)
+                                         -> ::std::result::Result<u64, D::Error>
+    {
+        deserialize_bits(self.0, deserializer)
+    }
+}
+
+#[test]
+fn test() {
+    let mut buf = [0xFF_u8; 64];
+    let len = {
+        let mut writer = BitWriter::new(&mut buf);
+        writer.write_bool(true).unwrap();
+        writer.write_bits(5, 3).unwrap();
+        writer.write_ranged(-3, -10, 10).unwrap(); // 5 bits
+        writer.write_varint(300).unwrap();
+        writer.write_signed_varint(-2).unwrap();
+        writer.write_f32(1.5).unwrap();
+        writer.write_quantized(0.25, -1.0, 1.0, 10).unwrap();
+        writer.write_bits(u64::MAX, 64).unwrap();
+        writer.write_bytes(b"hi").unwrap();
+        assert!(writer.write_ranged(11, -10, 10).is_err());
+        assert_eq!(writer.bits_written(), 1 + 3 + 5 + 16 + 8 + 32 + 10 + 64 + 16);
+        writer.bytes_written()
+    };
+    assert_eq!(len, 20);
+
+    let mut reader = BitReader::new(&buf[..len]);
+    assert!(reader.read_bool().unwrap());
+    assert_eq!(reader.read_bits(3).unwrap(), 5);
+    assert_eq!(reader.read_ranged(-10, 10).unwrap(), -3);
+    assert_eq!(reader.read_varint().unwrap(), 300);
+    assert_eq!(reader.read_signed_varint().unwrap(), -2);
+    assert_eq!(reader.read_f32().unwrap(), 1.5);
+    assert!((reader.read_quantized(-1.0, 1.0, 10).unwrap() - 0.25).abs() < 1.0 / 1023.0);
+    assert_eq!(reader.read_bits(64).unwrap(), u64::MAX);
+    let mut bytes = [0; 2];
+    reader.read_bytes(&mut bytes).unwrap();
+    assert_eq!(&bytes, b"hi");
+    // Only padding is left
+    assert!(reader.read_bits(8).is_err());
+
+    // Running out of room
+    let mut small = [0_u8; 1];
+    let mut writer = BitWriter::new(&mut small);
+    writer.write_bits(0, 6).unwrap();
+    match writer.write_bits(0, 3) {
+        Err(Error(ErrorKind::BufferTooSmall(2), _)) => {},
+        _ => panic!("Writing past the end of the buffer did not fail"),
+    }
+    let mut counter = BitWriter::counter();
+    counter.write_varint(1 << 20).unwrap();
+    assert_eq!(counter.bytes_written(), 3);
+
+    // A BitEncode value through serde
+    #[derive(Debug, PartialEq)]
+    struct Move {
+        running: bool,
+        heading: i64,
+    }
+    impl BitEncode for Move {
+        fn encode(&self, writer: &mut BitWriter) -> Result<()>
+        {
+            writer.write_bool(self.running)?;
+            writer.write_ranged(self.heading, 0, 359)
+        }
+        fn decode(reader: &mut BitReader) -> Result<Move>
+        {
+            Ok(Move { running: reader.read_bool()?, heading: reader.read_ranged(0, 359)? })
+        }
+    }
+    impl Serialize for Move {
+        fn serialize<S: Serializer>(&self, s: S) -> ::std::result::Result<S::Ok, S::Error>
+        {
+            serialize(self, s)
+        }
+    }
+    impl<'de> ::serde::Deserialize<'de> for Move {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> ::std::result::Result<Move, D::Error>
+        {
+            deserialize(d)
+        }
+    }
+    let m = Move { running: true, heading: 270 };
+    let bytes = ::bincode::serialize(&m).unwrap();
+    assert_eq!(bytes.len(), 4 + 2); // the bit count, and 10 bits
+    assert_eq!(::bincode::deserialize::<Move>(&bytes).unwrap(), m);
+}
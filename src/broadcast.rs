@@ -0,0 +1,168 @@
+
+use std::marker::PhantomData;
+use serde::Serialize;
+use errors::*;
+use remote::Remote;
+use packets::Packet;
+use pool::{BufferPool, PooledBuffer};
+use codec::{Codec, BincodeCodec};
+
+/// A packet encoded once, to be sent to many remotes.  Each remote only has
+/// to build its header and seal the datagram, which is far cheaper than
+/// calling `serialize_packet()` for each of them.
+///
+/// See `Connections::broadcast()`.  To seal on several threads, share the
+/// `Broadcast` and give each thread a group of remotes from
+/// `Connections::chunks_mut()`.
+pub struct Broadcast<C = BincodeCodec> {
+    body: Vec<u8>,
+    reply_expected: bool,
+    magic: u32,
+    version: u32,
+    codec: PhantomData<C>,
+}
+
+impl Broadcast {
+    pub fn new<P: Packet + Serialize>(packet: &P, magic: u32, version: u32) -> Result<Broadcast>
+    {
+        Broadcast::with_codec(packet, magic, version)
+    }
+}
+
+impl<C: Codec> Broadcast<C> {
+    /// Encode `packet` with the codec `C`, which must be the remotes' codec
+    pub fn with_codec<P: Packet + Serialize>(packet: &P, magic: u32, version: u32)
+                                             -> Result<Broadcast<C>>
+    {
+        let mut body = vec![0; C::serialized_size(packet)?];
+        let len = C::serialize_into(packet, &mut body[..])?;
+        body.truncate(len);
+        Ok(Broadcast {
+            body: body,
+            reply_expected: packet.reply_expected(),
+            magic: magic,
+            version: version,
+            codec: PhantomData,
+        })
+    }
+
+    /// The size of the encoded packet
+    pub fn body_len(&self) -> usize
+    {
+        self.body.len()
+    }
+
+    /// The datagram for `remote`, in a buffer taken from `pool`
+    pub fn seal(&self, remote: &mut Remote<C>, pool: &BufferPool) -> Result<PooledBuffer>
+    {
+        remote.seal_encoded_pooled(&self.body, self.reply_expected, self.magic, self.version,
+                                   pool)
+    }
+
+    /// Write the datagram for `remote` into `buf`.  Returns the number of
+    /// bytes written.
+    pub fn seal_into(&self, remote: &mut Remote<C>, buf: &mut [u8]) -> Result<usize>
+    {
+        remote.seal_encoded_into(&self.body, self.reply_expected, self.magic, self.version, buf)
+    }
+}
+
+#[test]
+fn test() {
+    use std::io;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::net::SocketAddr;
+    use ring::rand::SystemRandom;
+    use connections::Connections;
+    use pool::BufferPool;
+    use sender::{BatchSender, DatagramSink};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    #[packet(id = 310)]
+    struct MovePacket {
+        entity: u32,
+        position: [f32; 3],
+    }
+
+    // Blocks while its flag is set
+    struct Sink(Vec<(SocketAddr, Vec<u8>)>, bool);
+    impl DatagramSink for Sink {
+        fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize>
+        {
+            if self.1 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "full"));
+            }
+            self.0.push((addr, datagram.to_vec()));
+            Ok(datagram.len())
+        }
+    }
+
+    let rng = Arc::new(SystemRandom::new());
+    let server_addr: SocketAddr = FromStr::from_str("10.9.8.7:4444").unwrap();
+    let mut connections = Connections::new();
+    let mut clients = Vec::new();
+    for connection_id in 1..9 {
+        let addr: SocketAddr = FromStr::from_str(&format!("10.1.2.{}:5555", connection_id))
+            .unwrap();
+        let mut server = Remote::new(addr, rng.clone()).unwrap();
+        server.connection_id = connection_id;
+        connections.insert(server).unwrap();
+        let mut client = Remote::new(server_addr, rng.clone()).unwrap();
+        client.connection_id = connection_id;
+        clients.push(client);
+    }
+
+    let packet = MovePacket { entity: 7, position: [1.0, 2.0, 3.0] };
+    let broadcast = Broadcast::new(&packet, 0xABCDE000, 1).unwrap();
+    let pool = BufferPool::new(8);
+    let mut sender = BatchSender::new(Sink(Vec::new(), false), 64, 64);
+    let ids = [1, 2, 3, 5, 8, 13];
+    assert_eq!(connections.broadcast(&ids, &broadcast, &pool, &mut sender), 5);
+    sender.flush().unwrap();
+    assert_eq!(pool.stats().in_use, 0);
+
+    // Each remote gets its own datagram, which opens to the packet
+    let datagrams = sender.sink().0.clone();
+    assert_eq!(datagrams.len(), 5);
+    for (&id, (addr, mut bytes)) in ids.iter().zip(datagrams) {
+        assert_eq!(connections.get(id).unwrap().addr, addr);
+        let client = &mut clients[id as usize - 1];
+        let (received, _) = client.deserialize_packet::<MovePacket>(&mut bytes[..]).unwrap();
+        assert_eq!(received, packet);
+    }
+    assert_eq!(connections.get(4).unwrap().bytes_sent, 0);
+
+    // Sealed on worker threads, each with its own remotes
+    let pool = BufferPool::new(8);
+    let chunks = connections.chunks_mut(&[1, 2, 3, 2, 5, 8, 13], 3);
+    assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![2, 2, 1]);
+    let datagrams: Vec<(SocketAddr, PooledBuffer)> = ::std::thread::scope(|scope| {
+        let workers: Vec<_> = chunks.into_iter().map(|mut chunk| {
+            let (broadcast, pool) = (&broadcast, &pool);
+            scope.spawn(move || {
+                chunk.iter_mut()
+                    .map(|remote| (remote.addr, broadcast.seal(remote, pool).unwrap()))
+                    .collect::<Vec<_>>()
+            })
+        }).collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+    assert_eq!(datagrams.len(), 5);
+    for (&id, (addr, mut bytes)) in ids.iter().zip(datagrams) {
+        assert_eq!(connections.get(id).unwrap().addr, addr);
+        let client = &mut clients[id as usize - 1];
+        let (received, _) = client.deserialize_packet::<MovePacket>(&mut bytes[..]).unwrap();
+        assert_eq!(received, packet);
+    }
+
+    // Remotes are skipped when the pool or the send queue runs out, and the
+    // rest still get theirs
+    let pool = BufferPool::new(0);
+    assert_eq!(connections.broadcast(&ids, &broadcast, &pool, &mut sender), 0);
+    let pool = BufferPool::new(8);
+    let mut sender = BatchSender::new(Sink(Vec::new(), false), 2, 2);
+    sender.sink_mut().1 = true;
+    assert_eq!(connections.broadcast(&ids, &broadcast, &pool, &mut sender), 2);
+    assert_eq!(pool.stats().in_use, 2);
+}
@@ -0,0 +1,208 @@
+
+use errors::*;
+use std::fmt;
+use ring::aead::{Algorithm, AES_128_GCM, AES_256_GCM, CHACHA20_POLY1305,
+                 SealingKey, OpeningKey, seal_in_place, open_in_place};
+use ring::constant_time::verify_slices_are_equal;
+use packets::{NONCE_OFFSET, PREFIX_SIZE};
+
+/// The AEAD used to seal packets.  Negotiated during the handshake: the client
+/// offers the suites it supports in its `InitPacket` (most preferred first),
+/// and the server picks one in its `InitAckPacket`.  Handshake packets are
+/// always sealed with `Aes128Gcm` (under the all-zero key).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[repr(u8)]
+pub enum CipherSuite {
+    Aes128Gcm = 1,
+    Aes256Gcm = 2,
+    /// Faster than AES-GCM on hardware without AES acceleration
+    ChaCha20Poly1305 = 3,
+}
+
+impl CipherSuite {
+    /// Every supported suite, in the default order of preference
+    pub fn all() -> Vec<CipherSuite>
+    {
+        vec![CipherSuite::Aes128Gcm, CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305]
+    }
+
+    pub fn algorithm(&self) -> &'static Algorithm
+    {
+        match *self {
+            CipherSuite::Aes128Gcm => &AES_128_GCM,
+            CipherSuite::Aes256Gcm => &AES_256_GCM,
+            CipherSuite::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        }
+    }
+
+    /// The length of the key, which is the leading part of a session key
+    pub fn key_len(&self) -> usize
+    {
+        self.algorithm().key_len()
+    }
+
+    /// The number of bytes sealing adds to a packet
+    pub fn tag_len(&self) -> usize
+    {
+        self.algorithm().tag_len()
+    }
+}
+
+/// Pick the suite to use: the first of the client's `offered` suites which is
+/// also among the server's `supported` suites.
+pub fn negotiate(offered: &[CipherSuite], supported: &[CipherSuite]) -> Option<CipherSuite>
+{
+    offered.iter().find(|suite| supported.contains(suite)).cloned()
+}
+
+/// A session key along with its key schedules for sealing and opening, which
+/// are expanded once when the key is installed rather than for every packet.
+pub struct SessionKey {
+    cipher_suite: CipherSuite,
+    key: [u8; 32],
+    sealing_key: SealingKey,
+    opening_key: OpeningKey,
+}
+
+impl SessionKey {
+    /// Only the first `cipher_suite.key_len()` bytes of `key` are used
+    pub fn new(cipher_suite: CipherSuite, key: &[u8; 32]) -> Result<SessionKey>
+    {
+        let key_bytes = &key[..cipher_suite.key_len()];
+        Ok(SessionKey {
+            cipher_suite: cipher_suite,
+            key: *key,
+            sealing_key: SealingKey::new(cipher_suite.algorithm(), key_bytes)?,
+            opening_key: OpeningKey::new(cipher_suite.algorithm(), key_bytes)?,
+        })
+    }
+
+    /// The all-zero `Aes128Gcm` key that handshake packets are sealed under
+    pub fn handshake() -> SessionKey
+    {
+        // Cannot fail, the key is the right length
+        SessionKey::new(CipherSuite::Aes128Gcm, &[0; 32]).unwrap()
+    }
+
+    pub fn cipher_suite(&self) -> CipherSuite
+    {
+        self.cipher_suite
+    }
+
+    pub fn bytes(&self) -> &[u8; 32]
+    {
+        &self.key
+    }
+
+    /// Whether this is the all-zero key used before key exchange
+    pub fn is_handshake_key(&self) -> bool
+    {
+        self.key == [0; 32]
+    }
+
+    // Seal a datagram in place.  `bytes` holds the prefix, then the plaintext,
+    // then `tag_len()` bytes of room for the tag.  Returns the sealed length of
+    // everything after the prefix.
+    pub(crate) fn seal(&self, bytes: &mut [u8]) -> Result<usize>
+    {
+        if bytes.len() < PREFIX_SIZE { return Err(ErrorKind::InvalidPacket.into()); }
+        let (prefix, in_out) = bytes.split_at_mut(PREFIX_SIZE);
+        // The magic, version and connection id are the "associated data"
+        let (ad, nonce) = prefix.split_at(NONCE_OFFSET);
+        let size = seal_in_place(&self.sealing_key, nonce, ad, in_out,
+                                 self.cipher_suite.tag_len())?;
+        Ok(size)
+    }
+
+    // Open a datagram in place.  Returns the plaintext length; the plaintext
+    // starts at PREFIX_SIZE.
+    pub(crate) fn open(&self, bytes: &mut [u8]) -> Result<usize>
+    {
+        if bytes.len() < PREFIX_SIZE { return Err(ErrorKind::InvalidPacket.into()); }
+        let (prefix, in_out) = bytes.split_at_mut(PREFIX_SIZE);
+        let (ad, nonce) = prefix.split_at(NONCE_OFFSET);
+        let plaintext = open_in_place(&self.opening_key, nonce, ad, 0, in_out)?;
+        Ok(plaintext.len())
+    }
+}
+
+impl PartialEq for SessionKey {
+    fn eq(&self, other: &SessionKey) -> bool
+    {
+        self.cipher_suite == other.cipher_suite
+            && verify_slices_are_equal(&self.key[..], &other.key[..]).is_ok()
+    }
+}
+
+impl Eq for SessionKey { }
+
+// Never print the key itself
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "SessionKey({:?})", self.cipher_suite)
+    }
+}
+
+// Derive a session key from a shared secret with HKDF-SHA256.  `salt` binds it
+// to where the secret came from (the handshake transcript, or the key being
+// replaced); the suite and epoch go in the info, so that no two suites or
+// epochs ever share key bytes.
+pub(crate) fn derive_key(cipher_suite: CipherSuite, epoch: u32, salt: &[u8],
+                         shared_secret: &[u8], label: &[u8])
+                         -> Result<SessionKey>
+{
+    use ring::{digest, hkdf, hmac};
+
+    let salt = hmac::SigningKey::new(&digest::SHA256, salt);
+    let mut info: Vec<u8> = Vec::with_capacity(label.len() + 1 + 4);
+    info.extend_from_slice(label);
+    info.push(cipher_suite as u8);
+    info.extend_from_slice(&[epoch as u8, (epoch >> 8) as u8,
+                             (epoch >> 16) as u8, (epoch >> 24) as u8]);
+    let mut output: [u8; 32] = [0; 32];
+    hkdf::extract_and_expand(&salt, shared_secret, &info, &mut output);
+    SessionKey::new(cipher_suite, &output)
+}
+
+#[test]
+fn test() {
+    use self::CipherSuite::*;
+
+    assert_eq!(negotiate(&CipherSuite::all(), &CipherSuite::all()), Some(Aes128Gcm));
+    assert_eq!(negotiate(&[ChaCha20Poly1305, Aes128Gcm], &CipherSuite::all()),
+               Some(ChaCha20Poly1305));
+    assert_eq!(negotiate(&CipherSuite::all(), &[Aes256Gcm, ChaCha20Poly1305]),
+               Some(Aes256Gcm));
+    assert_eq!(negotiate(&[Aes128Gcm], &[ChaCha20Poly1305]), None);
+
+    assert_eq!(Aes128Gcm.key_len(), 16);
+    assert_eq!(Aes256Gcm.key_len(), 32);
+    assert_eq!(ChaCha20Poly1305.key_len(), 32);
+
+    // Keys are only equal for the same suite
+    let key = SessionKey::new(Aes256Gcm, &[3; 32]).unwrap();
+    assert_eq!(key, SessionKey::new(Aes256Gcm, &[3; 32]).unwrap());
+    assert!(key != SessionKey::new(ChaCha20Poly1305, &[3; 32]).unwrap());
+    assert!(SessionKey::handshake().is_handshake_key());
+
+    // Derived keys differ by suite and epoch, even from the same secret
+    let derived = derive_key(Aes256Gcm, 0, b"salt", &[5; 32], b"label").unwrap();
+    assert_eq!(derived, derive_key(Aes256Gcm, 0, b"salt", &[5; 32], b"label").unwrap());
+    assert!(derived.bytes() != derive_key(ChaCha20Poly1305, 0, b"salt", &[5; 32], b"label")
+            .unwrap().bytes());
+    assert!(derived.bytes() != derive_key(Aes256Gcm, 1, b"salt", &[5; 32], b"label")
+            .unwrap().bytes());
+    assert!(derived.bytes() != derive_key(Aes256Gcm, 0, b"other", &[5; 32], b"label")
+            .unwrap().bytes());
+    assert!(&derived.bytes()[..] != &[5; 32][..]);
+
+    // Seal and open in place
+    let mut bytes: Vec<u8> = vec![7; PREFIX_SIZE + 5 + 16];
+    let size = key.seal(&mut bytes[..]).unwrap();
+    assert_eq!(size, 5 + 16);
+    assert!(SessionKey::new(ChaCha20Poly1305, &[3; 32]).unwrap().open(&mut bytes.clone()[..])
+            .is_err());
+    assert_eq!(key.open(&mut bytes[..]).unwrap(), 5);
+    assert_eq!(&bytes[PREFIX_SIZE..PREFIX_SIZE + 5], &[7; 5]);
+}
@@ -0,0 +1,115 @@
+
+use serde::{Serialize, Deserialize};
+use errors::*;
+
+/// How packet bodies are encoded.  A `Remote` uses its codec for every packet
+/// it sends or receives, unless a different one is picked for a particular
+/// message with the `_with` variants of its methods, such as
+/// `Remote::serialize_packet_with()` and `Remote::deserialize_packet_with()`.
+/// The header is always encoded with bincode.
+///
+/// Codecs are chosen by type and keep no state.  `deserialize()` must ignore
+/// any bytes after the value.
+pub trait Codec {
+    /// The number of bytes `value` encodes to
+    fn serialized_size<T: Serialize + ?Sized>(value: &T) -> Result<usize>;
+
+    /// Encode `value` at the start of `buf`, returning the number of bytes
+    /// written.  `buf` is at least `serialized_size()` bytes long.
+    fn serialize_into<T: Serialize + ?Sized>(value: &T, buf: &mut [u8]) -> Result<usize>;
+
+    fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T>;
+}
+
+/// bincode's default encoding, with fixed width little endian integers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn serialized_size<T: Serialize + ?Sized>(value: &T) -> Result<usize>
+    {
+        Ok(::bincode::serialized_size(value)? as usize)
+    }
+
+    fn serialize_into<T: Serialize + ?Sized>(value: &T, buf: &mut [u8]) -> Result<usize>
+    {
+        let len = buf.len();
+        let mut writer = &mut buf[..];
+        ::bincode::serialize_into(&mut writer, value)?;
+        Ok(len - writer.len())
+    }
+
+    fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T>
+    {
+        Ok(::bincode::deserialize(bytes)?)
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::net::SocketAddr;
+    use ring::rand::SystemRandom;
+    use remote::Remote;
+    use packets::{Packet, HeartbeatPacket, InitAckPacket};
+    use cipher::CipherSuite;
+    use pool::BufferPool;
+
+    // bincode, behind a marker byte
+    struct MarkedCodec;
+    impl Codec for MarkedCodec {
+        fn serialized_size<T: Serialize + ?Sized>(value: &T) -> Result<usize>
+        {
+            Ok(1 + BincodeCodec::serialized_size(value)?)
+        }
+        fn serialize_into<T: Serialize + ?Sized>(value: &T, buf: &mut [u8]) -> Result<usize>
+        {
+            buf[0] = 0xAA;
+            Ok(1 + BincodeCodec::serialize_into(value, &mut buf[1..])?)
+        }
+        fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T>
+        {
+            if bytes.first() != Some(&0xAA) { return Err(ErrorKind::InvalidPacket.into()); }
+            BincodeCodec::deserialize(&bytes[1..])
+        }
+    }
+
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut client: Remote<MarkedCodec> = Remote::with_codec(addr, rng.clone()).unwrap();
+    let mut server: Remote<MarkedCodec> = Remote::with_codec(addr, rng.clone()).unwrap();
+    let mut plain = Remote::new(addr, rng).unwrap();
+
+    let init_ack = InitAckPacket::new(&client, CipherSuite::Aes128Gcm, &[7; 64]).unwrap();
+    assert!(!init_ack.reply_expected());
+    assert_eq!(client.serialized_packet_size(&init_ack).unwrap(),
+               plain.serialized_packet_size(&init_ack).unwrap() + 1);
+    let mut bytes = client.serialize_packet(&init_ack, 0xABCDE000, 1).unwrap();
+    let (received, _) = server.deserialize_packet::<InitAckPacket>(&mut bytes[..]).unwrap();
+    assert_eq!(received, init_ack);
+
+    // Another codec for one message
+    let mut bytes = client.serialize_packet_with::<BincodeCodec, _>(
+        &HeartbeatPacket::new(), 0xABCDE000, 1).unwrap();
+    let mut copy = bytes.clone();
+    server.deserialize_packet_with::<BincodeCodec, HeartbeatPacket>(&mut bytes[..]).unwrap();
+    assert!(plain.deserialize_packet::<HeartbeatPacket>(&mut copy[..]).is_ok());
+    let mut bytes = client.serialize_reply_packet_with::<BincodeCodec, _>(
+        &HeartbeatPacket::new(), 0xABCDE000, 1, 9).unwrap();
+    let (_, meta) = plain.deserialize_packet::<HeartbeatPacket>(&mut bytes[..]).unwrap();
+    assert_eq!(meta.in_reply_to, Some(9));
+    let mut buf = vec![0; client.serialized_packet_size_with::<BincodeCodec, _>(&init_ack)
+                              .unwrap()];
+    let len = client.serialize_packet_into_with::<BincodeCodec, _>(
+        &init_ack, 0xABCDE000, 1, &mut buf[..]).unwrap();
+    assert_eq!(plain.deserialize_packet::<InitAckPacket>(&mut buf[..len]).unwrap().0, init_ack);
+    let pool = BufferPool::new(1);
+    let mut pooled = client.serialize_packet_pooled_with::<BincodeCodec, _>(
+        &init_ack, 0xABCDE000, 1, &pool).unwrap();
+    assert_eq!(plain.deserialize_packet::<InitAckPacket>(&mut pooled[..]).unwrap().0, init_ack);
+
+    // The codecs do not mix
+    let mut bytes = plain.serialize_packet(&init_ack, 0xABCDE000, 1).unwrap();
+    assert!(server.deserialize_packet::<InitAckPacket>(&mut bytes[..]).is_err());
+}
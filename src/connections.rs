@@ -0,0 +1,317 @@
+
+use errors::*;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::{Values, ValuesMut};
+use std::net::SocketAddr;
+use remote::Remote;
+use interest::InterestGrid;
+use broadcast::Broadcast;
+use pool::BufferPool;
+use sender::{BatchSender, DatagramSink};
+use codec::{Codec, BincodeCodec};
+use packets::{peek_connection_id, PathResponsePacket};
+
+/// The set of remotes a server is talking to, indexed by connection id.
+///
+/// Datagrams are matched to a remote by the connection id they carry, so a
+/// remote whose address changes is still found.  Datagrams without one (from a
+/// client still in the handshake) are matched by address instead.
+pub struct Connections<C = BincodeCodec> {
+    remotes: HashMap<u64, Remote<C>>,
+    handshakes: HashMap<SocketAddr, u64>,
+    // The address each entry in `handshakes` is under, which may no longer be
+    // the remote's
+    handshake_addrs: HashMap<u64, SocketAddr>,
+}
+
+impl Connections {
+    pub fn new() -> Connections
+    {
+        Connections::default()
+    }
+}
+
+impl<C: Codec> Connections<C> {
+    /// Add a remote, which must have been assigned a connection id (see
+    /// `ServerIdentity::respond()`).
+    pub fn insert(&mut self, remote: Remote<C>) -> Result<()>
+    {
+        if remote.connection_id == 0 {
+            return Err("Remote has no connection id.".into());
+        }
+        if self.remotes.contains_key(&remote.connection_id) {
+            return Err("Connection id already in use.".into());
+        }
+        self.add_handshake(remote.addr, remote.connection_id);
+        self.remotes.insert(remote.connection_id, remote);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, connection_id: u64) -> Option<Remote<C>>
+    {
+        self.handshake_complete(connection_id);
+        self.remotes.remove(&connection_id)
+    }
+
+    pub fn get(&self, connection_id: u64) -> Option<&Remote<C>>
+    {
+        self.remotes.get(&connection_id)
+    }
+
+    pub fn get_mut(&mut self, connection_id: u64) -> Option<&mut Remote<C>>
+    {
+        self.remotes.get_mut(&connection_id)
+    }
+
+    /// Find the remote a datagram received from `from` belongs to.  The packet
+    /// still has to be opened by the remote before anything in it (including
+    /// the connection id) can be trusted.
+    pub fn lookup(&mut self, bytes: &[u8], from: SocketAddr) -> Result<Option<&mut Remote<C>>>
+    {
+        let connection_id = match peek_connection_id(bytes)? {
+            0 => match self.handshakes.get(&from) {
+                Some(connection_id) => *connection_id,
+                None => return Ok(None),
+            },
+            connection_id => connection_id,
+        };
+        Ok(self.remotes.get_mut(&connection_id))
+    }
+
+    /// Note that the remote has sent a packet under its connection id, so it
+    /// no longer needs to be found by address.
+    pub fn handshake_complete(&mut self, connection_id: u64)
+    {
+        if let Some(addr) = self.handshake_addrs.remove(&connection_id) {
+            self.handshakes.remove(&addr);
+        }
+    }
+
+    /// Handle a `PathResponsePacket` from the remote with `connection_id` (see
+    /// `Remote::validate_path_response()`).  If the remote moves, a handshake
+    /// datagram from `from` is matched to it from now on.
+    pub fn validate_path_response(&mut self, connection_id: u64,
+                                  response: &PathResponsePacket, from: SocketAddr) -> bool
+    {
+        let moved = match self.remotes.get_mut(&connection_id) {
+            Some(remote) => remote.validate_path_response(response, from),
+            None => return false,
+        };
+        if moved && self.handshake_addrs.contains_key(&connection_id) {
+            self.handshake_complete(connection_id);
+            self.add_handshake(from, connection_id);
+        }
+        moved
+    }
+
+    // Match handshake datagrams from `addr` to `connection_id`
+    fn add_handshake(&mut self, addr: SocketAddr, connection_id: u64)
+    {
+        if let Some(replaced) = self.handshakes.insert(addr, connection_id) {
+            self.handshake_addrs.remove(&replaced);
+        }
+        self.handshake_addrs.insert(connection_id, addr);
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.remotes.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.remotes.is_empty()
+    }
+
+    pub fn remotes(&self) -> Values<'_, u64, Remote<C>>
+    {
+        self.remotes.values()
+    }
+
+    pub fn remotes_mut(&mut self) -> ValuesMut<'_, u64, Remote<C>>
+    {
+        self.remotes.values_mut()
+    }
+
+    /// Call `f` with each remote whose view (in `interest`) contains
+    /// `position`, such as to send them an update about an entity there
+    pub fn for_each_viewer<F>(&mut self, interest: &InterestGrid, position: [f32; 2], mut f: F)
+                              -> Result<()>
+        where F: FnMut(&mut Remote<C>) -> Result<()>
+    {
+        for connection_id in interest.viewers(position) {
+            if let Some(remote) = self.remotes.get_mut(&connection_id) {
+                f(remote)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The remotes in `connection_ids`, split into at most `chunks` groups of
+    /// about the same size, such as to seal a `Broadcast` for each group on
+    /// its own worker thread.  A connection id listed twice, or not found, is
+    /// skipped.
+    pub fn chunks_mut(&mut self, connection_ids: &[u64], chunks: usize)
+                      -> Vec<Vec<&mut Remote<C>>>
+    {
+        assert!(chunks > 0);
+        let mut seen: HashSet<u64> = HashSet::with_capacity(connection_ids.len());
+        let mut remotes: Vec<&mut Remote<C>> = Vec::with_capacity(connection_ids.len());
+        for connection_id in connection_ids {
+            if !seen.insert(*connection_id) { continue; }
+            if let Some(remote) = self.remotes.get_mut(connection_id) {
+                // Each connection id is taken once, so these borrow distinct
+                // entries, and the map cannot change while they live
+                remotes.push(unsafe { &mut *(remote as *mut Remote<C>) });
+            }
+        }
+
+        let chunk_size = ::std::cmp::max(remotes.len().div_ceil(chunks), 1);
+        let mut groups = Vec::with_capacity(chunks);
+        while !remotes.is_empty() {
+            let rest = remotes.split_off(::std::cmp::min(chunk_size, remotes.len()));
+            groups.push(remotes);
+            remotes = rest;
+        }
+        groups
+    }
+
+    /// Seal `broadcast` for each remote in `connection_ids` (such as those
+    /// from `InterestGrid::viewers()`; a remote listed twice is sent two
+    /// datagrams) into buffers from `pool`, and queue the datagrams on
+    /// `sender`.  Remotes which cannot be sent to (such as an unvalidated
+    /// address at its amplification limit, or when `pool` or the send queue
+    /// is full) are skipped.  Returns the number of datagrams queued.
+    pub fn broadcast<S: DatagramSink>(&mut self, connection_ids: &[u64],
+                                      broadcast: &Broadcast<C>, pool: &BufferPool,
+                                      sender: &mut BatchSender<S>)
+                                      -> usize
+    {
+        let mut queued = 0;
+        for connection_id in connection_ids {
+            let remote = match self.remotes.get_mut(connection_id) {
+                Some(remote) => remote,
+                None => continue,
+            };
+            let sent = match broadcast.seal(remote, pool) {
+                Ok(datagram) => sender.push(remote.addr, datagram),
+                Err(e) => Err(e),
+            };
+            match sent {
+                Ok(()) => queued += 1,
+                Err(e) => debug!("Not broadcasting to {}: {}", connection_id, e),
+            }
+        }
+        queued
+    }
+}
+
+// For connections using a codec other than the default
+impl<C> Default for Connections<C> {
+    fn default() -> Connections<C>
+    {
+        Connections {
+            remotes: HashMap::new(),
+            handshakes: HashMap::new(),
+            handshake_addrs: HashMap::new(),
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use ring::rand::SystemRandom;
+    use identity::ServerIdentity;
+    use packets::{Packet, InitPacket, HeartbeatPacket, PathChallengePacket,
+                  PathResponsePacket, AMPLIFICATION_FACTOR};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    enum Message {
+        Heartbeat(HeartbeatPacket),
+    }
+    impl Packet for Message {
+        fn reply_expected(&self) -> bool { false }
+    }
+
+    let rng = Arc::new(SystemRandom::new());
+    let identity = ServerIdentity::from_pkcs8(
+        &ServerIdentity::generate_pkcs8(&*rng).unwrap()).unwrap();
+    let server_addr: SocketAddr = FromStr::from_str("10.9.8.7:4444").unwrap();
+    let wifi_addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mobile_addr: SocketAddr = FromStr::from_str("172.16.0.9:6001").unwrap();
+
+    let mut connections = Connections::new();
+    let mut client = Remote::new(server_addr, rng.clone()).unwrap();
+    let mut server = Remote::new(wifi_addr, rng.clone()).unwrap();
+    let init = InitPacket::new(&mut client).unwrap();
+    let init_ack = identity.respond(&mut server, &init, 1).unwrap();
+    client.complete_handshake(&init_ack, identity.public_key(), 1).unwrap();
+    let connection_id = server.connection_id;
+    assert!(connection_id != 0);
+    assert_eq!(client.connection_id, connection_id);
+    connections.insert(server).unwrap();
+
+    // Handshake datagrams (no connection id yet) are found by address
+    let mut stranger = Remote::new(server_addr, rng.clone()).unwrap();
+    let bytes = stranger.serialize_packet(&Message::Heartbeat(HeartbeatPacket::new()),
+                                         0xABCDE000, 1).unwrap();
+    assert_eq!(peek_connection_id(&bytes).unwrap(), 0);
+    assert!(connections.lookup(&bytes, wifi_addr).unwrap().is_some());
+    assert!(connections.lookup(&bytes, mobile_addr).unwrap().is_none());
+
+    // The client moves to another network
+    let mut bytes = client.serialize_packet(&Message::Heartbeat(HeartbeatPacket::new()),
+                                            0xABCDE000, 1).unwrap();
+    assert_eq!(peek_connection_id(&bytes).unwrap(), connection_id);
+    let challenge = {
+        let server = connections.lookup(&bytes, mobile_addr).unwrap().unwrap();
+        server.deserialize_packet_header::<Message>(&mut bytes[..]).unwrap();
+        server.check_source(mobile_addr, bytes.len()).unwrap().unwrap()
+    };
+    connections.handshake_complete(connection_id);
+
+    // Challenges repeated without anything more from the new address run into
+    // its amplification limit
+    {
+        let server = connections.get_mut(connection_id).unwrap();
+        while let Some(again) = server.check_source(mobile_addr, 0).unwrap() {
+            assert_eq!(again, challenge);
+        }
+        assert_eq!(server.path_bytes_received, bytes.len());
+        assert!(server.path_bytes_sent <= bytes.len() * AMPLIFICATION_FACTOR);
+    }
+    assert_eq!(connections.get(connection_id).unwrap().addr, wifi_addr);
+
+    // A response from elsewhere does not move the session
+    let response = PathResponsePacket::new(&challenge);
+    assert!(!connections.validate_path_response(connection_id, &PathResponsePacket::new(
+        &PathChallengePacket::new([0; 8])), mobile_addr));
+    assert!(!connections.validate_path_response(connection_id, &response, wifi_addr));
+    assert_eq!(connections.get(connection_id).unwrap().addr, wifi_addr);
+
+    assert!(connections.validate_path_response(connection_id, &response, mobile_addr));
+    let server = connections.get_mut(connection_id).unwrap();
+    assert_eq!(server.addr, mobile_addr);
+    assert!(server.check_source(mobile_addr, bytes.len()).unwrap().is_none());
+
+    // A remote which moves during the handshake is found at its new address,
+    // and at neither once it is removed
+    let mut other = Remote::new(wifi_addr, rng.clone()).unwrap();
+    identity.respond(&mut other, &InitPacket::new(&mut stranger).unwrap(), 1).unwrap();
+    let other_id = other.connection_id;
+    assert!(other.check_source(mobile_addr, 1).unwrap().is_none());
+    let challenge = other.check_source(mobile_addr, 100).unwrap().unwrap();
+    connections.insert(other).unwrap();
+    assert!(connections.validate_path_response(other_id, &PathResponsePacket::new(&challenge),
+                                               mobile_addr));
+    let bytes = stranger.serialize_packet(&Message::Heartbeat(HeartbeatPacket::new()),
+                                         0xABCDE000, 1).unwrap();
+    assert!(connections.lookup(&bytes, wifi_addr).unwrap().is_none());
+    assert_eq!(connections.lookup(&bytes, mobile_addr).unwrap().unwrap().connection_id,
+               other_id);
+    connections.remove(other_id).unwrap();
+    assert!(connections.lookup(&bytes, mobile_addr).unwrap().is_none());
+    assert!(connections.handshakes.is_empty() && connections.handshake_addrs.is_empty());
+}
@@ -0,0 +1,190 @@
+
+use errors::*;
+use std::net::{SocketAddr, IpAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ring::{digest, hmac};
+use ring::rand::SecureRandom;
+
+/// A token the server hands to a client in a `RetryPacket`, proving (when the
+/// client echoes it back in its `InitPacket`) that the client can receive
+/// packets at its source address.  The server keeps no state for it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[repr(C)]
+pub struct Cookie {
+    /// When the cookie was issued, in seconds since the unix epoch
+    pub issued: u64,
+    mac: [u8; 32],
+}
+
+/// Issues and verifies cookies, with a secret key that never leaves the server.
+///
+/// With a `CookieJar`, a server does not allocate a `Remote` (which costs an
+/// X25519 key generation) for an `InitPacket` unless it carries a valid
+/// cookie.  Otherwise it only answers with a `RetryPacket`, using a remote
+/// from `Remote::new_unkeyed()` that is dropped afterwards.  A valid cookie
+/// also proves the client's address, so the server may set
+/// `Remote::address_validated`.
+pub struct CookieJar {
+    key: hmac::SigningKey,
+    lifetime: Duration,
+}
+
+impl CookieJar {
+    pub fn new(rng: &dyn SecureRandom, lifetime: Duration) -> Result<CookieJar>
+    {
+        Ok(CookieJar {
+            key: hmac::SigningKey::generate(&digest::SHA256, rng)?,
+            lifetime: lifetime,
+        })
+    }
+
+    /// Issue a cookie for a client at `addr`
+    pub fn issue(&self, addr: &SocketAddr) -> Cookie
+    {
+        let issued = unix_time();
+        let mut mac: [u8; 32] = [0; 32];
+        mac.copy_from_slice(hmac::sign(&self.key, &cookie_data(issued, addr)).as_ref());
+        Cookie {
+            issued: issued,
+            mac: mac,
+        }
+    }
+
+    /// Check that `cookie` was issued by us, to `addr`, and has not expired
+    pub fn verify(&self, cookie: &Cookie, addr: &SocketAddr) -> bool
+    {
+        let now = unix_time();
+        if cookie.issued > now || now - cookie.issued > self.lifetime.as_secs() {
+            return false;
+        }
+        hmac::verify_with_own_key(&self.key, &cookie_data(cookie.issued, addr),
+                                  &cookie.mac[..]).is_ok()
+    }
+}
+
+// The data a cookie's MAC covers
+fn cookie_data(issued: u64, addr: &SocketAddr) -> Vec<u8>
+{
+    let mut data: Vec<u8> = Vec::with_capacity(8 + 16 + 2);
+    for i in 0..8 {
+        data.push((issued >> (i * 8)) as u8);
+    }
+    match addr.ip() {
+        IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()[..]),
+        IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()[..]),
+    }
+    data.push(addr.port() as u8);
+    data.push((addr.port() >> 8) as u8);
+    data
+}
+
+pub(crate) fn unix_time() -> u64
+{
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use ring::rand::SystemRandom;
+
+    let rng = SystemRandom::new();
+    let jar = CookieJar::new(&rng, Duration::from_secs(30)).unwrap();
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let other: SocketAddr = FromStr::from_str("10.1.2.3:5556").unwrap();
+
+    let cookie = jar.issue(&addr);
+    assert!(jar.verify(&cookie, &addr));
+    assert!(!jar.verify(&cookie, &other));
+
+    // Another server's cookie is refused
+    let other_jar = CookieJar::new(&rng, Duration::from_secs(30)).unwrap();
+    assert!(!other_jar.verify(&cookie, &addr));
+
+    // Tampering with the time is detected
+    let mut forged = cookie.clone();
+    forged.issued -= 1;
+    assert!(!jar.verify(&forged, &addr));
+
+    // Expired cookies are refused
+    let mut expired = cookie.clone();
+    expired.issued -= 31;
+    expired.mac.copy_from_slice(
+        hmac::sign(&jar.key, &cookie_data(expired.issued, &addr)).as_ref());
+    assert!(!jar.verify(&expired, &addr));
+}
+
+#[test]
+fn test_retry() {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use ring::rand::SystemRandom;
+    use remote::Remote;
+    use identity::ServerIdentity;
+    use packets::{Packet, InitPacket, RetryPacket};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    enum Handshake {
+        Init(Box<InitPacket>),
+        Retry(RetryPacket),
+    }
+    impl Packet for Handshake {
+        fn reply_expected(&self) -> bool { true }
+    }
+
+    let rng = Arc::new(SystemRandom::new());
+    let jar = CookieJar::new(&*rng, Duration::from_secs(30)).unwrap();
+    let identity = ServerIdentity::from_pkcs8(
+        &ServerIdentity::generate_pkcs8(&*rng).unwrap()).unwrap();
+    let client_addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let server_addr: SocketAddr = FromStr::from_str("10.9.8.7:4444").unwrap();
+    let mut client = Remote::new(server_addr, rng.clone()).unwrap();
+
+    // First Init carries no cookie, so the server only answers with a Retry
+    let init = Handshake::Init(Box::new(InitPacket::new(&mut client).unwrap()));
+    let mut bytes = client.serialize_packet(&init, 0xABCDE000, 1).unwrap();
+    let mut server = Remote::new_unkeyed(client_addr, rng.clone());
+    let init = {
+        let (body, _, _) = server.deserialize_packet_header::<Handshake>(&mut bytes[..]).unwrap();
+        ::bincode::deserialize::<Handshake>(body).unwrap()
+    };
+    match init {
+        Handshake::Init(ref init) => assert_eq!(init.cookie, None),
+        _ => panic!("Expected an Init"),
+    }
+    let retry = Handshake::Retry(RetryPacket::new(jar.issue(&client_addr)));
+    let mut bytes = server.serialize_packet(&retry, 0xABCDE000, 1).unwrap();
+    drop(server);
+
+    // The client echoes the cookie
+    let retry = {
+        let (body, _, _) = client.deserialize_packet_header::<Handshake>(&mut bytes[..]).unwrap();
+        ::bincode::deserialize::<Handshake>(body).unwrap()
+    };
+    match retry {
+        Handshake::Retry(retry) => client.cookie = Some(retry.cookie),
+        _ => panic!("Expected a Retry"),
+    }
+    let init = Handshake::Init(Box::new(InitPacket::new(&mut client).unwrap()));
+    let mut bytes = client.serialize_packet(&init, 0xABCDE000, 1).unwrap();
+
+    // Now the server commits to a full remote
+    let mut server = Remote::new_unkeyed(client_addr, rng.clone());
+    let init = {
+        let (body, _, _) = server.deserialize_packet_header::<Handshake>(&mut bytes[..]).unwrap();
+        ::bincode::deserialize::<Handshake>(body).unwrap()
+    };
+    let init = match init {
+        Handshake::Init(init) => init,
+        _ => panic!("Expected an Init"),
+    };
+    assert!(jar.verify(init.cookie.as_ref().unwrap(), &client_addr));
+    server.address_validated = true;
+    server.generate_ephemeral_key().unwrap();
+    let init_ack = identity.respond(&mut server, &init, 1).unwrap();
+    client.complete_handshake(&init_ack, identity.public_key(), 1).unwrap();
+    assert_eq!(server.pending_session_key.as_ref(), Some(&client.session_key));
+}
@@ -40,5 +40,22 @@ error_chain! {
         RemoteFailedChallenge {
             description("Remote failed challenge"),
         }
+        InvalidConnectToken(reason: String) {
+            description("Invalid connect token"),
+            display("Invalid connect token: {}", reason),
+        }
+        BufferTooSmall(needed: usize) {
+            description("Buffer too small"),
+            display("Buffer too small, {} bytes are needed", needed),
+        }
+        PoolExhausted {
+            description("No buffer left in the pool"),
+        }
+        AmplificationLimit {
+            description("Sending would exceed the amplification limit for an unvalidated address"),
+        }
+        SendQueueFull {
+            description("The send queue is full"),
+        }
     }
 }
@@ -0,0 +1,436 @@
+
+use errors::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+use ring::digest::{digest, SHA256};
+use ring::rand::SecureRandom;
+use ring::signature::{Ed25519KeyPair, ED25519};
+use untrusted::Input;
+use remote::Remote;
+use codec::Codec;
+use packets::{InitPacket, InitAckPacket, ConnectPacket, ClientAuth, handshake_transcript,
+              client_auth_transcript};
+use token::ConnectTokenKey;
+use cookie::unix_time;
+
+// Sealed connect token => (when it expires, SHA-256 of the transcript)
+type UsedTokens = HashMap<Vec<u8>, (u64, Vec<u8>)>;
+
+/// The long-term Ed25519 identity of a server.  Clients know the public key
+/// in advance, and use it to verify the server's answer to their challenge.
+///
+/// It also decides when a session is established: right after the handshake,
+/// or (if connect tokens or client keys are required) once the client's
+/// `ConnectPacket` carries a valid token and proof of an authorized identity.
+pub struct ServerIdentity {
+    key_pair: Ed25519KeyPair,
+    connect_tokens: Option<(ConnectTokenKey, u64)>,
+    authorized_clients: Option<HashSet<[u8; 32]>>,
+    // Connect tokens already presented, until they expire, each bound to the
+    // handshake transcript of the session which used it
+    used_tokens: Mutex<UsedTokens>,
+}
+
+impl ServerIdentity {
+    /// Load an identity from a PKCS#8 (v2) document
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<ServerIdentity>
+    {
+        let key_pair = Ed25519KeyPair::from_pkcs8(Input::from(pkcs8))?;
+        Ok(ServerIdentity {
+            key_pair: key_pair,
+            connect_tokens: None,
+            authorized_clients: None,
+            used_tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Load an identity from a file containing a PKCS#8 (v2) document
+    pub fn from_pkcs8_file<P: AsRef<Path>>(path: P) -> Result<ServerIdentity>
+    {
+        ServerIdentity::from_pkcs8(&read_file(path)?)
+    }
+
+    /// Generate a new PKCS#8 (v2) document, suitable for `from_pkcs8()`.  Save
+    /// it somewhere safe, and distribute the public key to clients.
+    pub fn generate_pkcs8(rng: &dyn SecureRandom) -> Result<Vec<u8>>
+    {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(rng)?;
+        Ok(pkcs8.to_vec())
+    }
+
+    /// Require clients to present a connect token sealed with `key` and
+    /// issued for `server_id` before their session is established.
+    pub fn require_connect_tokens(mut self, key: ConnectTokenKey, server_id: u64)
+                                  -> ServerIdentity
+    {
+        self.connect_tokens = Some((key, server_id));
+        self
+    }
+
+    /// Require clients to prove they hold one of these Ed25519 identity keys
+    /// (see `ClientIdentity`) before their session is established.  Meant for
+    /// links between servers, and trusted tools.
+    pub fn require_client_keys<I>(mut self, public_keys: I) -> ServerIdentity
+        where I: IntoIterator<Item = [u8; 32]>
+    {
+        self.authorized_clients = Some(public_keys.into_iter().collect());
+        self
+    }
+
+    /// The public key clients should use to verify this server
+    pub fn public_key(&self) -> &[u8]
+    {
+        self.key_pair.public_key_bytes()
+    }
+
+    /// Sign a handshake transcript (see `packets::handshake_transcript()`)
+    pub fn sign_transcript(&self, transcript: &[u8]) -> [u8; 64]
+    {
+        let mut signature: [u8; 64] = [0; 64];
+        signature.copy_from_slice(self.key_pair.sign(transcript).as_ref());
+        signature
+    }
+
+    /// Answer a client's `InitPacket`.  The returned `InitAckPacket` carries our
+    /// signature over the handshake transcript and the connection id assigned
+    /// to `remote`, and the session key of `remote` is computed.  It takes
+    /// effect when the client's first packet under it arrives (see
+    /// `Remote::pending_session_key`).
+    pub fn respond<C: Codec>(&self, remote: &mut Remote<C>, init: &InitPacket, version: u32)
+                   -> Result<InitAckPacket>
+    {
+        let mut public_key = [0_u8; 32];
+        match remote.eph_private_key {
+            Some(ref eph) => eph.compute_public_key(&mut public_key)?,
+            None => return Err("Ephemeral private key already used.".into()),
+        }
+        let cipher_suite = remote.negotiate_cipher_suite(&init.cipher_suites)?;
+        let transcript = handshake_transcript(version, &init.public_key, &public_key,
+                                              &init.nonce, &init.cipher_suites, cipher_suite);
+        let signature = self.sign_transcript(&transcript);
+        remote.assign_connection_id()?;
+        let init_ack = InitAckPacket::new(remote, cipher_suite, &signature)?;
+        remote.handshake_transcript = Some(transcript);
+        remote.compute_pending_session_key(&init.public_key, cipher_suite)?;
+        remote.established = self.connect_tokens.is_none()
+            && self.authorized_clients.is_none();
+        Ok(init_ack)
+    }
+
+    /// Handle the client's `ConnectPacket` (which arrives under the session key,
+    /// after our `InitAckPacket`).  If it authorizes the client, the session is
+    /// established.
+    ///
+    /// A connect token is only good for the session which first presents it;
+    /// it is refused on any other until it expires.
+    pub fn accept_connect<C: Codec>(&self, remote: &mut Remote<C>, packet: &ConnectPacket) -> Result<()>
+    {
+        if remote.pending_session_key.is_some() || remote.session_key.is_handshake_key() {
+            return Err("ConnectPacket did not arrive under the session key.".into());
+        }
+        let (transcript, transcript_hash) = match remote.handshake_transcript {
+            Some(ref transcript) => (client_auth_transcript(transcript),
+                                     digest(&SHA256, transcript).as_ref().to_vec()),
+            None => return Err("Handshake has not completed.".into()),
+        };
+
+        if let Some(ref authorized_clients) = self.authorized_clients {
+            let client_auth = match packet.client_auth {
+                Some(ref client_auth) => client_auth,
+                None => return Err(ErrorKind::RemoteFailedChallenge.into()),
+            };
+            if !authorized_clients.contains(&client_auth.public_key) {
+                warn!("Unauthorized client key from {}", remote.addr);
+                return Err(ErrorKind::RemoteFailedChallenge.into());
+            }
+            ::ring::signature::verify(&ED25519,
+                                      Input::from(&client_auth.public_key),
+                                      Input::from(&transcript),
+                                      Input::from(&client_auth.get_signature()))
+                .map_err(|_| Error::from_kind(ErrorKind::RemoteFailedChallenge))?;
+        }
+
+        let token = match self.connect_tokens {
+            Some((ref key, server_id)) => match packet.token {
+                Some(ref sealed) => {
+                    let token = key.validate(sealed, server_id)?;
+                    self.use_token(sealed.0.clone(), token.expires, transcript_hash)?;
+                    Some(token)
+                },
+                None => return Err(ErrorKind::InvalidConnectToken("Missing".to_owned()).into()),
+            },
+            None => None,
+        };
+
+        if let Some(ref token) = token {
+            debug!("Player {} connected from {}", token.player_id, remote.addr);
+        }
+        remote.connect_token = token;
+        remote.client_public_key = packet.client_auth.as_ref().map(|auth| auth.public_key);
+        remote.established = true;
+        Ok(())
+    }
+
+    // Record that the session with `transcript_hash` presented `sealed`,
+    // refusing it if another session already has
+    fn use_token(&self, sealed: Vec<u8>, expires: u64, transcript_hash: Vec<u8>) -> Result<()>
+    {
+        let mut used_tokens = self.used_tokens.lock()
+            .map_err(|_| Error::from("Used connect tokens poisoned"))?;
+        let now = unix_time();
+        used_tokens.retain(|_, &mut (expires, _)| expires > now);
+        match used_tokens.get(&sealed) {
+            Some((_, used_by)) if *used_by != transcript_hash =>
+                return Err(ErrorKind::InvalidConnectToken("Already used".to_owned()).into()),
+            Some(_) => return Ok(()),
+            None => {},
+        }
+        used_tokens.insert(sealed, (expires, transcript_hash));
+        Ok(())
+    }
+}
+
+/// The long-term Ed25519 identity of a client, for servers which only accept
+/// known clients (see `ServerIdentity::require_client_keys()`).
+pub struct ClientIdentity {
+    key_pair: Ed25519KeyPair,
+}
+
+impl ClientIdentity {
+    /// Load an identity from a PKCS#8 (v2) document
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<ClientIdentity>
+    {
+        let key_pair = Ed25519KeyPair::from_pkcs8(Input::from(pkcs8))?;
+        Ok(ClientIdentity {
+            key_pair: key_pair,
+        })
+    }
+
+    /// Load an identity from a file containing a PKCS#8 (v2) document
+    pub fn from_pkcs8_file<P: AsRef<Path>>(path: P) -> Result<ClientIdentity>
+    {
+        ClientIdentity::from_pkcs8(&read_file(path)?)
+    }
+
+    /// The public key to authorize on the server
+    pub fn public_key(&self) -> [u8; 32]
+    {
+        let mut public_key: [u8; 32] = [0; 32];
+        public_key.copy_from_slice(self.key_pair.public_key_bytes());
+        public_key
+    }
+
+    /// Prove our identity to the server, over the handshake just completed with
+    /// `Remote::complete_handshake()`.  Send the result in the `ConnectPacket`.
+    pub fn authenticate<C: Codec>(&self, remote: &Remote<C>) -> Result<ClientAuth>
+    {
+        let transcript = match remote.handshake_transcript {
+            Some(ref transcript) => client_auth_transcript(transcript),
+            None => return Err("Handshake has not completed.".into()),
+        };
+        let signature = self.key_pair.sign(&transcript);
+        Ok(ClientAuth::new(self.public_key(), signature.as_ref()))
+    }
+}
+
+fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>>
+{
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut file = File::open(path)?;
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use ring::rand::SystemRandom;
+    use packets::{Packet, HeartbeatPacket};
+    use cipher::CipherSuite;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    enum Handshake {
+        InitAck(InitAckPacket),
+        Heartbeat(HeartbeatPacket),
+    }
+    impl Packet for Handshake {
+        fn reply_expected(&self) -> bool { false }
+    }
+
+    let rng = Arc::new(SystemRandom::new());
+    let pkcs8 = ServerIdentity::generate_pkcs8(&*rng).unwrap();
+    let identity = ServerIdentity::from_pkcs8(&pkcs8).unwrap();
+
+    let addr: SocketAddr = FromStr::from_str("127.0.0.1:5555").unwrap();
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng.clone()).unwrap();
+    client.cipher_suites = vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes128Gcm];
+
+    let init = InitPacket::new(&mut client).unwrap();
+    let init_ack = identity.respond(&mut server, &init, 3).unwrap();
+    assert_eq!(init_ack.cipher_suite, CipherSuite::ChaCha20Poly1305);
+
+    // The signature does not hold for another cipher suite, so an attacker
+    // cannot force a weaker one
+    let mut downgraded = init_ack.clone();
+    downgraded.cipher_suite = CipherSuite::Aes128Gcm;
+    assert!(client.validate_handshake_signature(&downgraded, identity.public_key(), 3)
+            .is_err());
+
+    // The signature does not hold for another version
+    assert!(client.validate_handshake_signature(&init_ack, identity.public_key(), 4)
+            .is_err());
+
+    // Nor from another server
+    let other = ServerIdentity::from_pkcs8(&ServerIdentity::generate_pkcs8(&*rng).unwrap())
+        .unwrap();
+    assert!(client.validate_handshake_signature(&init_ack, other.public_key(), 3)
+            .is_err());
+
+    // The InitAck is still sealed under the handshake key
+    assert!(server.session_key.is_handshake_key());
+    let mut bytes = server.serialize_packet(&Handshake::InitAck(init_ack), 0xABCDE000, 3)
+        .unwrap();
+    let init_ack = {
+        let (body, _, _) = client.deserialize_packet_header::<Handshake>(&mut bytes[..]).unwrap();
+        match ::bincode::deserialize::<Handshake>(body).unwrap() {
+            Handshake::InitAck(init_ack) => init_ack,
+            _ => panic!("Expected an InitAck"),
+        }
+    };
+    client.complete_handshake(&init_ack, identity.public_key(), 3).unwrap();
+    assert_eq!(client.cipher_suite(), CipherSuite::ChaCha20Poly1305);
+    assert_eq!(server.pending_session_key.as_ref(), Some(&client.session_key));
+
+    // The client's first packet under the session key moves the server over
+    let mut bytes = client.serialize_packet(&Handshake::Heartbeat(HeartbeatPacket::new()),
+                                            0xABCDE000, 3).unwrap();
+    assert!(server.session_key.is_handshake_key());
+    server.deserialize_packet_header::<Handshake>(&mut bytes[..]).unwrap();
+    assert_eq!(server.session_key, client.session_key);
+    assert_eq!(server.cipher_suite(), CipherSuite::ChaCha20Poly1305);
+    assert!(server.pending_session_key.is_none());
+
+    // Servers refuse clients with no cipher suite in common
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng.clone()).unwrap();
+    client.cipher_suites = vec![CipherSuite::ChaCha20Poly1305];
+    server.cipher_suites = vec![CipherSuite::Aes128Gcm, CipherSuite::Aes256Gcm];
+    let init = InitPacket::new(&mut client).unwrap();
+    assert!(identity.respond(&mut server, &init, 3).is_err());
+}
+
+#[test]
+fn test_connect_token() {
+    use std::str::FromStr;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use ring::rand::SystemRandom;
+    use token::ConnectToken;
+
+    let rng = Arc::new(SystemRandom::new());
+    let backend_key = ConnectTokenKey::new(&[3; 32]).unwrap();
+    let identity = ServerIdentity::from_pkcs8(&ServerIdentity::generate_pkcs8(&*rng).unwrap())
+        .unwrap()
+        .require_connect_tokens(ConnectTokenKey::new(&[3; 32]).unwrap(), 77);
+
+    let addr: SocketAddr = FromStr::from_str("127.0.0.1:5555").unwrap();
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng.clone()).unwrap();
+    let init = InitPacket::new(&mut client).unwrap();
+    let init_ack = identity.respond(&mut server, &init, 1).unwrap();
+    client.complete_handshake(&init_ack, identity.public_key(), 1).unwrap();
+    assert!(!server.established);
+
+    // Until the session key is in use, even a good token is refused
+    let mut token = ConnectToken {
+        player_id: 42,
+        expires: unix_time() + 30,
+        server_id: 77,
+        user_data: vec![],
+    };
+    let sealed = backend_key.seal(&token, &*rng).unwrap();
+    assert!(identity.accept_connect(&mut server, &ConnectPacket::new(Some(sealed), None)).is_err());
+    let mut bytes = client.serialize_packet(&ConnectPacket::new(None, None), 0xABCDE000, 1)
+        .unwrap();
+    server.deserialize_packet::<ConnectPacket>(&mut bytes[..]).unwrap();
+
+    // Without a token, or with one for another server, the session is refused
+    assert!(identity.accept_connect(&mut server, &ConnectPacket::new(None, None)).is_err());
+    token.server_id = 78;
+    let sealed = backend_key.seal(&token, &*rng).unwrap();
+    assert!(identity.accept_connect(&mut server, &ConnectPacket::new(Some(sealed), None)).is_err());
+    assert!(!server.established);
+
+    token.server_id = 77;
+    let sealed = backend_key.seal(&token, &*rng).unwrap();
+    identity.accept_connect(&mut server, &ConnectPacket::new(Some(sealed.clone()), None)).unwrap();
+    assert!(server.established);
+    assert_eq!(server.connect_token.as_ref().unwrap().player_id, 42);
+
+    // The token may be presented again in the same session, but not in another
+    identity.accept_connect(&mut server, &ConnectPacket::new(Some(sealed.clone()), None)).unwrap();
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng.clone()).unwrap();
+    let init = InitPacket::new(&mut client).unwrap();
+    let init_ack = identity.respond(&mut server, &init, 1).unwrap();
+    client.complete_handshake(&init_ack, identity.public_key(), 1).unwrap();
+    let mut bytes = client.serialize_packet(&ConnectPacket::new(None, None), 0xABCDE000, 1)
+        .unwrap();
+    server.deserialize_packet::<ConnectPacket>(&mut bytes[..]).unwrap();
+    assert!(identity.accept_connect(&mut server, &ConnectPacket::new(Some(sealed), None)).is_err());
+    assert!(!server.established);
+}
+
+#[test]
+fn test_client_auth() {
+    use std::str::FromStr;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use ring::rand::SystemRandom;
+
+    let rng = Arc::new(SystemRandom::new());
+    let tool = ClientIdentity::from_pkcs8(&ServerIdentity::generate_pkcs8(&*rng).unwrap())
+        .unwrap();
+    let intruder = ClientIdentity::from_pkcs8(&ServerIdentity::generate_pkcs8(&*rng).unwrap())
+        .unwrap();
+    let identity = ServerIdentity::from_pkcs8(&ServerIdentity::generate_pkcs8(&*rng).unwrap())
+        .unwrap()
+        .require_client_keys(vec![tool.public_key()]);
+
+    let addr: SocketAddr = FromStr::from_str("127.0.0.1:5555").unwrap();
+    let handshake = || {
+        let mut client = Remote::new(addr, rng.clone()).unwrap();
+        let mut server = Remote::new(addr, rng.clone()).unwrap();
+        let init = InitPacket::new(&mut client).unwrap();
+        let init_ack = identity.respond(&mut server, &init, 1).unwrap();
+        client.complete_handshake(&init_ack, identity.public_key(), 1).unwrap();
+        let mut bytes = client.serialize_packet(&ConnectPacket::new(None, None), 0xABCDE000, 1)
+            .unwrap();
+        server.deserialize_packet::<ConnectPacket>(&mut bytes[..]).unwrap();
+        assert!(!server.established);
+        (client, server)
+    };
+
+    // An unknown key is refused, as is a missing one
+    let (client, mut server) = handshake();
+    let auth = intruder.authenticate(&client).unwrap();
+    assert!(identity.accept_connect(&mut server, &ConnectPacket::new(None, Some(auth))).is_err());
+    assert!(identity.accept_connect(&mut server, &ConnectPacket::new(None, None)).is_err());
+
+    // A signature from another session is refused
+    let (other_client, _) = handshake();
+    let auth = tool.authenticate(&other_client).unwrap();
+    assert!(identity.accept_connect(&mut server, &ConnectPacket::new(None, Some(auth))).is_err());
+    assert!(!server.established);
+
+    let auth = tool.authenticate(&client).unwrap();
+    identity.accept_connect(&mut server, &ConnectPacket::new(None, Some(auth))).unwrap();
+    assert!(server.established);
+    assert_eq!(server.client_public_key, Some(tool.public_key()));
+}
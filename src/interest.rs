@@ -0,0 +1,274 @@
+
+use std::collections::{HashMap, HashSet};
+use errors::*;
+
+type Cell = (i32, i32);
+
+// The most cells a view region may span across
+const MAX_VIEW_SPAN: i64 = 1024;
+
+/// The area a remote can see: everything within `radius` of `center`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewRegion {
+    pub center: [f32; 2],
+    pub radius: f32,
+}
+
+impl ViewRegion {
+    pub fn new(center: [f32; 2], radius: f32) -> Result<ViewRegion>
+    {
+        let view = ViewRegion {
+            center: center,
+            radius: radius,
+        };
+        view.validate()?;
+        Ok(view)
+    }
+
+    // The center must be finite, and the radius finite and not negative
+    fn validate(&self) -> Result<()>
+    {
+        if !self.center[0].is_finite() || !self.center[1].is_finite() {
+            return Err("View center is not finite.".into());
+        }
+        if !self.radius.is_finite() || self.radius < 0.0 {
+            return Err("View radius must be finite and not negative.".into());
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, position: [f32; 2]) -> bool
+    {
+        let dx = position[0] - self.center[0];
+        let dy = position[1] - self.center[1];
+        dx * dx + dy * dy <= self.radius * self.radius
+    }
+}
+
+/// Tracks where entities are and what each remote (by connection id) can
+/// see, so that an update about an entity is only sent to the remotes which
+/// can see it.  Both are indexed in a grid of square cells, so a query only
+/// looks at the views overlapping one cell.  A cell a little smaller than
+/// the typical view radius works well.
+///
+/// Positions are two dimensional; in a 3D world use the ground plane.
+pub struct InterestGrid {
+    cell_size: f32,
+    entities: HashMap<u32, [f32; 2]>,
+    entity_cells: HashMap<Cell, HashSet<u32>>,
+    views: HashMap<u64, ViewRegion>,
+    view_cells: HashMap<Cell, HashSet<u64>>,
+}
+
+impl InterestGrid {
+    pub fn new(cell_size: f32) -> InterestGrid
+    {
+        assert!(cell_size > 0.0);
+        InterestGrid {
+            cell_size: cell_size,
+            entities: HashMap::new(),
+            entity_cells: HashMap::new(),
+            views: HashMap::new(),
+            view_cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, position: [f32; 2]) -> Cell
+    {
+        ((position[0] / self.cell_size).floor() as i32,
+         (position[1] / self.cell_size).floor() as i32)
+    }
+
+    // The cells a view region overlaps
+    fn cells(&self, view: &ViewRegion) -> Vec<Cell>
+    {
+        let (x0, y0) = self.cell([view.center[0] - view.radius, view.center[1] - view.radius]);
+        let (x1, y1) = self.cell([view.center[0] + view.radius, view.center[1] + view.radius]);
+        let mut cells = Vec::new();
+        for x in x0..=x1 {
+            for y in y0..=y1 {
+                cells.push((x, y));
+            }
+        }
+        cells
+    }
+
+    /// Add or move an entity
+    pub fn set_entity(&mut self, entity: u32, position: [f32; 2])
+    {
+        let cell = self.cell(position);
+        if let Some(old) = self.entities.insert(entity, position) {
+            let old = self.cell(old);
+            if old == cell { return; }
+            remove_from(&mut self.entity_cells, old, &entity);
+        }
+        self.entity_cells.entry(cell).or_default().insert(entity);
+    }
+
+    pub fn remove_entity(&mut self, entity: u32)
+    {
+        if let Some(position) = self.entities.remove(&entity) {
+            let cell = self.cell(position);
+            remove_from(&mut self.entity_cells, cell, &entity);
+        }
+    }
+
+    pub fn entity(&self, entity: u32) -> Option<[f32; 2]>
+    {
+        self.entities.get(&entity).cloned()
+    }
+
+    /// Set or move the view region of the remote with `connection_id`.  Fails
+    /// (leaving the old view in place) if the view is invalid, or spans more
+    /// than 1024 cells across.
+    pub fn set_view(&mut self, connection_id: u64, view: ViewRegion) -> Result<()>
+    {
+        view.validate()?;
+        let (x0, y0) = self.cell([view.center[0] - view.radius, view.center[1] - view.radius]);
+        let (x1, y1) = self.cell([view.center[0] + view.radius, view.center[1] + view.radius]);
+        if x1 as i64 - x0 as i64 >= MAX_VIEW_SPAN || y1 as i64 - y0 as i64 >= MAX_VIEW_SPAN {
+            return Err("View spans too many cells.".into());
+        }
+
+        self.remove_view(connection_id);
+        for cell in self.cells(&view) {
+            self.view_cells.entry(cell).or_default().insert(connection_id);
+        }
+        self.views.insert(connection_id, view);
+        Ok(())
+    }
+
+    /// Forget a remote's view, such as when it disconnects
+    pub fn remove_view(&mut self, connection_id: u64)
+    {
+        if let Some(view) = self.views.remove(&connection_id) {
+            for cell in self.cells(&view) {
+                remove_from(&mut self.view_cells, cell, &connection_id);
+            }
+        }
+    }
+
+    pub fn view(&self, connection_id: u64) -> Option<ViewRegion>
+    {
+        self.views.get(&connection_id).cloned()
+    }
+
+    /// The connection ids of the remotes which can see `position`
+    pub fn viewers(&self, position: [f32; 2]) -> Vec<u64>
+    {
+        match self.view_cells.get(&self.cell(position)) {
+            Some(connection_ids) => connection_ids.iter()
+                .filter(|id| self.views[id].contains(position))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The connection ids of the remotes which can see `entity`
+    pub fn viewers_of(&self, entity: u32) -> Vec<u64>
+    {
+        match self.entities.get(&entity) {
+            Some(&position) => self.viewers(position),
+            None => Vec::new(),
+        }
+    }
+
+    /// The entities the remote with `connection_id` can see, such as to send
+    /// it everything when it first arrives
+    pub fn visible_entities(&self, connection_id: u64) -> Vec<u32>
+    {
+        let view = match self.views.get(&connection_id) {
+            Some(view) => view,
+            None => return Vec::new(),
+        };
+        self.cells(view).iter()
+            .filter_map(|cell| self.entity_cells.get(cell))
+            .flat_map(|entities| entities.iter())
+            .filter(|entity| view.contains(self.entities[entity]))
+            .cloned()
+            .collect()
+    }
+}
+
+fn remove_from<T: ::std::hash::Hash + Eq>(cells: &mut HashMap<Cell, HashSet<T>>, cell: Cell,
+                                          item: &T)
+{
+    let empty = match cells.get_mut(&cell) {
+        Some(items) => {
+            items.remove(item);
+            items.is_empty()
+        },
+        None => false,
+    };
+    if empty {
+        cells.remove(&cell);
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::net::SocketAddr;
+    use ring::rand::SystemRandom;
+    use connections::Connections;
+    use remote::Remote;
+
+    let sorted = |mut v: Vec<u64>| { v.sort(); v };
+
+    let mut grid = InterestGrid::new(10.0);
+    grid.set_view(1, ViewRegion::new([0.0, 0.0], 15.0).unwrap()).unwrap();
+    grid.set_view(2, ViewRegion::new([20.0, 0.0], 15.0).unwrap()).unwrap();
+    grid.set_view(3, ViewRegion::new([-500.0, -500.0], 15.0).unwrap()).unwrap();
+
+    grid.set_entity(100, [10.0, 0.0]);
+    grid.set_entity(101, [-14.0, -1.0]);
+    grid.set_entity(102, [12.0, 14.0]); // in the right cells, but out of range
+    assert_eq!(sorted(grid.viewers_of(100)), vec![1, 2]);
+    assert_eq!(grid.viewers_of(101), vec![1]);
+    assert!(grid.viewers_of(102).is_empty());
+    assert!(grid.viewers_of(999).is_empty());
+    assert_eq!(grid.visible_entities(2), vec![100]);
+    let mut visible = grid.visible_entities(1);
+    visible.sort();
+    assert_eq!(visible, vec![100, 101]);
+
+    // Entities and views move
+    grid.set_entity(100, [-490.0, -500.0]);
+    assert_eq!(grid.viewers_of(100), vec![3]);
+    grid.set_view(3, ViewRegion::new([500.0, 500.0], 15.0).unwrap()).unwrap();
+    assert!(grid.viewers_of(100).is_empty());
+    grid.remove_view(1);
+    assert!(grid.viewers_of(101).is_empty());
+    grid.remove_entity(101);
+    assert_eq!(grid.entity(101), None);
+    assert!(grid.visible_entities(2).is_empty());
+
+    // Views must be sane, and not too big for the grid
+    assert!(ViewRegion::new([0.0, 0.0], -1.0).is_err());
+    assert!(ViewRegion::new([0.0, 0.0], f32::NAN).is_err());
+    assert!(ViewRegion::new([f32::INFINITY, 0.0], 1.0).is_err());
+    let mut bad = grid.view(2).unwrap();
+    bad.radius = f32::INFINITY;
+    assert!(grid.set_view(2, bad).is_err());
+    assert!(grid.set_view(2, ViewRegion::new([0.0, 0.0], 1e9).unwrap()).is_err());
+    assert_eq!(grid.view(2).unwrap().center, [20.0, 0.0]);
+
+    // Only the remotes which can see an entity hear about it
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut connections = Connections::new();
+    for connection_id in 1..4 {
+        let mut remote = Remote::new(addr, rng.clone()).unwrap();
+        remote.connection_id = connection_id;
+        connections.insert(remote).unwrap();
+    }
+    grid.set_view(1, ViewRegion::new([500.0, 500.0], 15.0).unwrap()).unwrap();
+    let mut told = Vec::new();
+    connections.for_each_viewer(&grid, [505.0, 500.0], |remote| {
+        told.push(remote.connection_id);
+        Ok(())
+    }).unwrap();
+    assert_eq!(sorted(told), vec![1, 3]);
+}
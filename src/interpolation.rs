@@ -0,0 +1,233 @@
+
+use std::collections::VecDeque;
+use errors::*;
+use timestamp::Timestamp;
+use remote::{Remote, PacketMeta};
+use codec::Codec;
+
+/// State which can be blended between two samples.  `t` runs from 0 (`self`)
+/// to 1 (`other`), and goes past 1 when extrapolating.
+pub trait Interpolate {
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, other: &f32, t: f32) -> f32
+    {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for f64 {
+    fn interpolate(&self, other: &f64, t: f32) -> f64
+    {
+        self + (other - self) * t as f64
+    }
+}
+
+impl Interpolate for [f32; 2] {
+    fn interpolate(&self, other: &[f32; 2], t: f32) -> [f32; 2]
+    {
+        [self[0].interpolate(&other[0], t), self[1].interpolate(&other[1], t)]
+    }
+}
+
+impl Interpolate for [f32; 3] {
+    fn interpolate(&self, other: &[f32; 3], t: f32) -> [f32; 3]
+    {
+        [self[0].interpolate(&other[0], t),
+         self[1].interpolate(&other[1], t),
+         self[2].interpolate(&other[2], t)]
+    }
+}
+
+// How quickly the jitter estimate follows new samples (as in RFC 3550)
+const JITTER_GAIN: f32 = 1.0 / 16.0;
+
+// How many times the jitter is added to the minimum delay
+const JITTER_MULTIPLIER: f32 = 3.0;
+
+/// Plays back state received from a remote a little in the past, so that
+/// there is usually a sample on either side of the moment being shown.
+///
+/// Samples are stamped with the `timestamp` of the packet which carried them
+/// (the remote's clock), and played back at `remote.now() - delay()`.  The
+/// delay grows with the jitter observed in packet arrival times.  If no
+/// sample has arrived for the moment being shown, the last two samples are
+/// extrapolated, for at most `max_extrapolation` milliseconds.
+pub struct InterpolationBuffer<T> {
+    samples: VecDeque<(Timestamp, T)>,
+    capacity: usize,
+    min_delay: i32,
+    max_delay: i32,
+    max_extrapolation: i32,
+    jitter: f32,
+    last_transit: Option<i32>,
+}
+
+impl<T: Interpolate + Clone> InterpolationBuffer<T> {
+    /// `min_delay` should be at least the interval at which the remote sends
+    /// samples.  Delays are in milliseconds.
+    pub fn new(capacity: usize, min_delay: i32, max_delay: i32, max_extrapolation: i32)
+               -> InterpolationBuffer<T>
+    {
+        assert!(capacity > 1);
+        assert!(min_delay <= max_delay);
+        InterpolationBuffer {
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+            min_delay: min_delay,
+            max_delay: max_delay,
+            max_extrapolation: max_extrapolation,
+            jitter: 0.0,
+            last_transit: None,
+        }
+    }
+
+    /// The estimated jitter of packet arrival times, in milliseconds
+    pub fn jitter(&self) -> f32
+    {
+        self.jitter
+    }
+
+    /// How far behind the remote's clock playback currently runs, in milliseconds
+    pub fn delay(&self) -> i32
+    {
+        let delay = self.min_delay + (self.jitter * JITTER_MULTIPLIER).ceil() as i32;
+        ::std::cmp::min(delay, self.max_delay)
+    }
+
+    /// The number of samples buffered
+    pub fn len(&self) -> usize
+    {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.samples.is_empty()
+    }
+
+    /// Buffer `state`, which arrived in the packet described by `meta`
+    pub fn receive<C: Codec>(&mut self, remote: &Remote<C>, meta: &PacketMeta, state: T)
+                             -> Result<()>
+    {
+        let arrival = remote.now()?;
+        self.push(meta.timestamp, arrival, state);
+        Ok(())
+    }
+
+    /// Buffer `state`, sent at `timestamp` and received at `arrival` (both by
+    /// the remote's clock).  Samples may arrive out of order; duplicates and
+    /// samples too old to fit are dropped.
+    pub fn push(&mut self, timestamp: Timestamp, arrival: Timestamp, state: T)
+    {
+        let transit = arrival - timestamp;
+        if let Some(last_transit) = self.last_transit {
+            let deviation = (transit - last_transit).abs() as f32;
+            self.jitter += (deviation - self.jitter) * JITTER_GAIN;
+        }
+        self.last_transit = Some(transit);
+
+        // Keep the samples in order, newest last
+        let mut index = self.samples.len();
+        while index > 0 && self.samples[index - 1].0 - timestamp > 0 {
+            index -= 1;
+        }
+        if index > 0 && self.samples[index - 1].0 == timestamp { return; }
+        if self.samples.len() == self.capacity {
+            if index == 0 { return; }
+            self.samples.pop_front();
+            index -= 1;
+        }
+        self.samples.insert(index, (timestamp, state));
+    }
+
+    /// The state to show now
+    pub fn sample<C: Codec>(&self, remote: &Remote<C>) -> Result<Option<T>>
+    {
+        let now = remote.now()?;
+        Ok(self.sample_at(now + (-self.delay())))
+    }
+
+    /// The state at `time` (by the remote's clock), or `None` if nothing has
+    /// been received
+    pub fn sample_at(&self, time: Timestamp) -> Option<T>
+    {
+        let (first, last) = match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return None,
+        };
+        if time - first.0 <= 0 { return Some(first.1.clone()); }
+
+        if time - last.0 >= 0 {
+            // Ran out of samples, so carry on from the last two
+            if self.samples.len() < 2 { return Some(last.1.clone()); }
+            let before = &self.samples[self.samples.len() - 2];
+            let ahead = ::std::cmp::min(time - last.0, self.max_extrapolation);
+            let t = 1.0 + ahead as f32 / (last.0 - before.0) as f32;
+            return Some(before.1.interpolate(&last.1, t));
+        }
+
+        let index = self.samples.iter().position(|&(stamp, _)| stamp - time > 0).unwrap();
+        let (from, to) = (&self.samples[index - 1], &self.samples[index]);
+        let t = (time - from.0) as f32 / (to.0 - from.0) as f32;
+        Some(from.1.interpolate(&to.1, t))
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::net::SocketAddr;
+    use ring::rand::SystemRandom;
+
+    let at = |ms: u32| Timestamp::from_raw(ms);
+    let mut buffer: InterpolationBuffer<[f32; 2]> = InterpolationBuffer::new(4, 50, 200, 100);
+    assert_eq!(buffer.sample_at(at(0)), None);
+
+    // Samples every 50ms, arriving 20ms later without jitter
+    buffer.push(at(1000), at(1020), [0.0, 0.0]);
+    assert_eq!(buffer.sample_at(at(2000)), Some([0.0, 0.0]));
+    buffer.push(at(1100), at(1120), [10.0, 20.0]);
+    buffer.push(at(1050), at(1070), [5.0, 10.0]); // out of order
+    buffer.push(at(1050), at(1070), [9.0, 9.0]); // duplicate
+    assert_eq!(buffer.len(), 3);
+    assert_eq!(buffer.jitter(), 0.0);
+    assert_eq!(buffer.delay(), 50);
+
+    assert_eq!(buffer.sample_at(at(900)), Some([0.0, 0.0]));
+    assert_eq!(buffer.sample_at(at(1025)), Some([2.5, 5.0]));
+    assert_eq!(buffer.sample_at(at(1050)), Some([5.0, 10.0]));
+    assert_eq!(buffer.sample_at(at(1075)), Some([7.5, 15.0]));
+
+    // Late packets are extrapolated, up to a limit
+    assert_eq!(buffer.sample_at(at(1150)), Some([15.0, 30.0]));
+    assert_eq!(buffer.sample_at(at(1500)), Some([20.0, 40.0]));
+
+    // The oldest sample is dropped when full, and older ones are refused
+    buffer.push(at(1150), at(1170), [15.0, 30.0]);
+    buffer.push(at(1200), at(1220), [20.0, 40.0]);
+    assert_eq!(buffer.len(), 4);
+    assert_eq!(buffer.sample_at(at(0)), Some([5.0, 10.0]));
+    buffer.push(at(1000), at(1230), [0.0, 0.0]);
+    assert_eq!(buffer.sample_at(at(0)), Some([5.0, 10.0]));
+
+    // Jitter increases the delay, up to the maximum
+    for i in 0..100 {
+        let late = if i % 2 == 0 { 0 } else { 80 };
+        buffer.push(at(2000 + i * 50), at(2020 + i * 50 + late), [0.0, 0.0]);
+    }
+    assert!(buffer.jitter() > 60.0);
+    assert_eq!(buffer.delay(), 200);
+
+    // Playback follows the remote's clock, once it is known
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut remote = Remote::new(addr, rng).unwrap();
+    assert!(buffer.sample(&remote).is_err());
+    remote.offset_min = Some(0);
+    remote.offset_max = Some(0);
+    assert_eq!(buffer.sample(&remote).unwrap(), Some([0.0, 0.0]));
+}
@@ -16,12 +16,59 @@ extern crate log;
 extern crate lazy_static;
 #[macro_use]
 extern crate error_chain;
+extern crate siege_net_derive;
+
+// Lets code from `#[derive(Packet)]` name this crate as `::siege_net` in here too
+extern crate self as siege_net;
 
 mod errors;
 mod timestamp;
 pub mod packets;
 mod remote;
+mod rekey;
+mod identity;
+mod cookie;
+mod connections;
+mod ratelimit;
+mod token;
+mod cipher;
+mod pool;
+mod router;
+mod codec;
+pub mod bits;
+mod bitcodec;
+pub mod quantize;
+mod snapshot;
+mod interpolation;
+mod interest;
+mod sender;
+mod broadcast;
+mod scheduler;
 
 pub use errors::*;
 pub use timestamp::Timestamp;
-pub use remote::Remote;
+pub use remote::{Remote, PacketMeta};
+pub use rekey::{RekeyPolicy, RekeyState};
+pub use identity::{ServerIdentity, ClientIdentity};
+pub use cookie::{Cookie, CookieJar};
+pub use connections::Connections;
+pub use ratelimit::{RateLimiter, RateLimitConfig, RateLimitStats, Verdict};
+pub use token::{ConnectToken, SealedConnectToken, ConnectTokenKey, MAX_TOKEN_USER_DATA};
+pub use cipher::{CipherSuite, SessionKey, negotiate};
+pub use pool::{BufferPool, PooledBuffer, PoolStats};
+pub use router::Router;
+pub use codec::{Codec, BincodeCodec};
+pub use bitcodec::BitCodec;
+pub use snapshot::{Snapshot, SnapshotDelta, SnapshotSender, SnapshotReceiver};
+pub use interpolation::{Interpolate, InterpolationBuffer};
+pub use interest::{InterestGrid, ViewRegion};
+pub use sender::{DatagramSink, BatchSender};
+pub use broadcast::Broadcast;
+pub use scheduler::{Scheduler, IfUnsent};
+
+// Used by the code `#[derive(MessageSet)]` generates
+#[doc(hidden)]
+pub mod __private {
+    pub use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    pub use serde::de::Error as DeError;
+}
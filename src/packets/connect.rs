@@ -0,0 +1,57 @@
+
+use packets::Packet;
+use token::SealedConnectToken;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Packet)]
+#[packet(id = 8)]
+#[repr(C)]
+pub struct ConnectPacket {
+    pub token: Option<SealedConnectToken>,
+    pub client_auth: Option<ClientAuth>,
+}
+
+impl ConnectPacket {
+    pub fn new(token: Option<SealedConnectToken>, client_auth: Option<ClientAuth>)
+               -> ConnectPacket
+    {
+        ConnectPacket {
+            token: token,
+            client_auth: client_auth,
+        }
+    }
+}
+
+// A client's proof of its identity: its Ed25519 public key and its signature
+// over the handshake (see `client_auth_transcript()`).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[repr(C)]
+pub struct ClientAuth {
+    pub public_key: [u8; 32],
+    signature_1: [u8; 32],
+    signature_2: [u8; 32],
+}
+
+impl ClientAuth {
+    pub fn new(public_key: [u8; 32], signature: &[u8]) -> ClientAuth
+    {
+        let mut signature_1: [u8; 32] = [0; 32];
+        signature_1.copy_from_slice(&signature[0..32]);
+
+        let mut signature_2: [u8; 32] = [0; 32];
+        signature_2.copy_from_slice(&signature[32..64]);
+
+        ClientAuth {
+            public_key: public_key,
+            signature_1: signature_1,
+            signature_2: signature_2,
+        }
+    }
+
+    pub fn get_signature(&self) -> [u8; 64]
+    {
+        let mut signature: [u8; 64] = [0; 64];
+        signature[0..32].copy_from_slice(&self.signature_1[..]);
+        signature[32..64].copy_from_slice(&self.signature_2[..]);
+        signature
+    }
+}
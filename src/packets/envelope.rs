@@ -0,0 +1,256 @@
+
+use std::fmt;
+use std::marker::PhantomData;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::ser::{self, SerializeTuple};
+use serde::de::{self, DeserializeSeed, SeqAccess, Visitor};
+use errors::*;
+use super::*;
+
+/// The id of a kind of message, known without having one of them.  Implemented
+/// by `#[derive(Packet)]` on structs.
+pub trait MessageId {
+    const MESSAGE_ID: u16;
+}
+
+/// An enum of messages which travel in an `Envelope`, each identified on the
+/// wire by its message id.  Implemented with `#[derive(MessageSet)]` on an enum
+/// whose variants each wrap one packet.
+pub trait MessageSet: Packet + Sized {
+    /// The ids of all the messages in the set
+    const MESSAGE_IDS: &'static [u16];
+
+    /// Serialize the wrapped packet, without any variant tag
+    fn serialize_body<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: Serializer;
+
+    /// Deserialize the packet with message id `id`
+    fn deserialize_body<'de, D>(id: u16, deserializer: D) -> ::std::result::Result<Self, D::Error>
+        where D: Deserializer<'de>;
+}
+
+/// siege-net's own packets.  Their ids are all below
+/// `MIN_APPLICATION_MESSAGE_ID`.
+#[derive(Debug, PartialEq, Clone, Packet, MessageSet)]
+#[allow(clippy::large_enum_variant)]
+pub enum ControlPacket {
+    Init(InitPacket),
+    InitAck(InitAckPacket),
+    Heartbeat(HeartbeatPacket),
+    HeartbeatAck(HeartbeatAckPacket),
+    Shutdown(ShutdownPacket),
+    ShutdownComplete(ShutdownCompletePacket),
+    UpgradeRequired(UpgradeRequiredPacket),
+    Connect(ConnectPacket),
+    Retry(RetryPacket),
+    PathChallenge(PathChallengePacket),
+    PathResponse(PathResponsePacket),
+    Rekey(RekeyPacket),
+    RekeyAck(RekeyAckPacket),
+}
+
+/// Either one of siege-net's control packets or one of the application's own
+/// messages `M`.  This is what goes in a datagram: the message id followed by
+/// the message, so applications never re-declare the control packets and their
+/// numbering is fixed by the ids rather than by the order of enum variants.
+#[derive(Debug, PartialEq, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Envelope<M> {
+    Control(ControlPacket),
+    Message(M),
+}
+
+impl<M: MessageSet> Packet for Envelope<M> {
+    fn reply_expected(&self) -> bool
+    {
+        match *self {
+            Envelope::Control(ref p) => p.reply_expected(),
+            Envelope::Message(ref m) => m.reply_expected(),
+        }
+    }
+
+    fn message_id(&self) -> u16
+    {
+        match *self {
+            Envelope::Control(ref p) => p.message_id(),
+            Envelope::Message(ref m) => m.message_id(),
+        }
+    }
+
+    fn channel(&self) -> u8
+    {
+        match *self {
+            Envelope::Control(ref p) => p.channel(),
+            Envelope::Message(ref m) => m.channel(),
+        }
+    }
+
+    fn reliability(&self) -> Reliability
+    {
+        match *self {
+            Envelope::Control(ref p) => p.reliability(),
+            Envelope::Message(ref m) => m.reliability(),
+        }
+    }
+}
+
+/// Check that a set of application messages stays out of the reserved id range
+/// and does not use any id twice.  Worth calling once at startup.
+pub fn check_message_set<M: MessageSet>() -> Result<()>
+{
+    let ids = M::MESSAGE_IDS;
+    for (i, &id) in ids.iter().enumerate() {
+        if id < MIN_APPLICATION_MESSAGE_ID {
+            return Err(format!("Message id {} is reserved for siege-net", id).into());
+        }
+        if ids[..i].contains(&id) {
+            return Err(format!("Message id {} is used twice", id).into());
+        }
+    }
+    Ok(())
+}
+
+impl<M: MessageSet> Serialize for Envelope<M> {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let id = self.message_id();
+        if let Envelope::Message(_) = *self {
+            if id < MIN_APPLICATION_MESSAGE_ID {
+                return Err(ser::Error::custom(
+                    format_args!("message id {} is reserved for siege-net", id)));
+            }
+        }
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&id)?;
+        tuple.serialize_element(&Body(self))?;
+        tuple.end()
+    }
+}
+
+struct Body<'a, M: 'a>(&'a Envelope<M>);
+
+impl<'a, M: MessageSet> Serialize for Body<'a, M> {
+    fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        match *self.0 {
+            Envelope::Control(ref p) => p.serialize_body(serializer),
+            Envelope::Message(ref m) => m.serialize_body(serializer),
+        }
+    }
+}
+
+impl<'de, M: MessageSet> Deserialize<'de> for Envelope<M> {
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Envelope<M>, D::Error>
+        where D: Deserializer<'de>
+    {
+        deserializer.deserialize_tuple(2, EnvelopeVisitor(PhantomData))
+    }
+}
+
+struct EnvelopeVisitor<M>(PhantomData<M>);
+
+impl<'de, M: MessageSet> Visitor<'de> for EnvelopeVisitor<M> {
+    type Value = Envelope<M>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str("a message id followed by a message")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> ::std::result::Result<Envelope<M>, A::Error>
+        where A: SeqAccess<'de>
+    {
+        let id: u16 = match seq.next_element()? {
+            Some(id) => id,
+            None => return Err(de::Error::invalid_length(0, &self)),
+        };
+        let envelope = if id < MIN_APPLICATION_MESSAGE_ID {
+            seq.next_element_seed(BodySeed::<ControlPacket>(id, PhantomData))?
+                .map(Envelope::Control)
+        } else {
+            seq.next_element_seed(BodySeed::<M>(id, PhantomData))?
+                .map(Envelope::Message)
+        };
+        envelope.ok_or_else(|| de::Error::invalid_length(1, &self))
+    }
+}
+
+struct BodySeed<M>(u16, PhantomData<M>);
+
+impl<'de, M: MessageSet> DeserializeSeed<'de> for BodySeed<M> {
+    type Value = M;
+
+    fn deserialize<D>(self, deserializer: D) -> ::std::result::Result<M, D::Error>
+        where D: Deserializer<'de>
+    {
+        M::deserialize_body(self.0, deserializer)
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::net::SocketAddr;
+    use ring::rand::SystemRandom;
+    use remote::Remote;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    #[packet(id = 300, channel = 1, reliability = "reliable")]
+    struct ChatPacket {
+        text: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    #[packet(id = 301)]
+    struct MovePacket {
+        x: f32,
+        y: f32,
+    }
+
+    // The order of the variants does not matter on the wire
+    #[derive(Debug, PartialEq, Clone, Packet, MessageSet)]
+    enum Game {
+        Move(MovePacket),
+        Chat(ChatPacket),
+    }
+
+    #[derive(Debug, PartialEq, Clone, Packet, MessageSet)]
+    enum OtherGame {
+        Chat(ChatPacket),
+        Move(MovePacket),
+        Heartbeat(HeartbeatPacket),
+    }
+
+    check_message_set::<Game>().unwrap();
+    assert!(check_message_set::<OtherGame>().is_err());
+    assert_eq!(ControlPacket::MESSAGE_IDS.len(), 13);
+    assert!(ControlPacket::MESSAGE_IDS.iter().all(|&id| id < MIN_APPLICATION_MESSAGE_ID));
+
+    let chat: Envelope<Game> = Envelope::Message(Game::Chat(ChatPacket { text: "hi".to_owned() }));
+    let bytes = ::bincode::serialize(&chat).unwrap();
+    assert_eq!(&bytes[..2], &[44, 1]); // 300, little endian
+    assert_eq!(::bincode::deserialize::<Envelope<OtherGame>>(&bytes).unwrap(),
+               Envelope::Message(OtherGame::Chat(ChatPacket { text: "hi".to_owned() })));
+    assert_eq!(chat.channel(), 1);
+
+    // Application messages may not use a reserved id
+    let reserved: Envelope<OtherGame> = Envelope::Message(OtherGame::Heartbeat(HeartbeatPacket));
+    assert!(::bincode::serialize(&reserved).is_err());
+
+    // Control packets and messages share a connection
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng).unwrap();
+    for envelope in [Envelope::Control(ControlPacket::Heartbeat(HeartbeatPacket::new())),
+                         Envelope::Message(Game::Move(MovePacket { x: 1.0, y: -2.0 })),
+                         chat]
+    {
+        let mut bytes = client.serialize_packet(&envelope, 0xABCDE000, 1).unwrap();
+        let (received, _) = server.deserialize_packet::<Envelope<Game>>(&mut bytes[..]).unwrap();
+        assert_eq!(received, envelope);
+    }
+}
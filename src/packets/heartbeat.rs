@@ -1,5 +1,8 @@
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+use packets::Packet;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Packet)]
+#[packet(id = 3, reply_expected)]
 #[repr(C)]
 pub struct HeartbeatPacket;
 
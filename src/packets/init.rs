@@ -1,16 +1,27 @@
 
 use errors::*;
 use remote::Remote;
+use codec::Codec;
+use cookie::Cookie;
+use cipher::CipherSuite;
+use packets::Packet;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Packet)]
+#[packet(id = 1, reply_expected)]
 #[repr(C)]
 pub struct InitPacket {
     pub public_key: [u8; 32],
     pub nonce: [u8; 12],
+    pub cookie: Option<Cookie>,
+    /// The cipher suites the client supports, most preferred first
+    pub cipher_suites: Vec<CipherSuite>,
+    // Keeps this packet larger than any response sent before the client's
+    // address is validated, so the server cannot be used to amplify traffic.
+    padding: [[u8; 32]; 8],
 }
 
 impl InitPacket {
-    pub fn new(remote: &mut Remote) -> Result<InitPacket>
+    pub fn new<C: Codec>(remote: &mut Remote<C>) -> Result<InitPacket>
     {
         if remote.eph_private_key.is_none() {
             return Err("Ephemeral private key already used.".into());
@@ -23,6 +34,9 @@ impl InitPacket {
         Ok(InitPacket {
             public_key: public_key,
             nonce: remote.nonce,
+            cookie: remote.cookie.clone(),
+            cipher_suites: remote.cipher_suites.clone(),
+            padding: [[0; 32]; 8],
         })
     }
 }
@@ -40,15 +54,20 @@ fn test() {
     enum Packet {
         Init(InitPacket),
     }
+    impl ::packets::Packet for Packet {
+        fn reply_expected(&self) -> bool { true }
+    }
 
     let remote_addr: SocketAddr = FromStr::from_str("0.0.0.0:0").unwrap();
     let mut remote = Remote::new(remote_addr, Arc::new(SystemRandom::new())).unwrap();
 
     let init_packet = InitPacket::new(&mut remote).unwrap();
     let packet = Packet::Init(init_packet.clone());
-    let mut bytes: Vec<u8> = remote.serialize_packet(&packet).unwrap();
-    let (packet2,_,stale) = remote.deserialize_packet(&mut bytes[..]).unwrap();
-    assert_eq!(stale,false);
+    let mut bytes: Vec<u8> = remote.serialize_packet(&packet, 0xABCDE000, 1).unwrap();
+    let (packet2,meta) = remote.deserialize_packet::<Packet>(&mut bytes[..]).unwrap();
+    assert!(!meta.stale);
+    assert_eq!(meta.sequence_number,1);
+    assert_eq!(meta.in_reply_to,None);
     match packet2 {
         Packet::Init(init_packet2) => {
             assert_eq!(init_packet2, init_packet);
@@ -1,16 +1,23 @@
 
 use errors::*;
 use remote::Remote;
+use codec::Codec;
+use cipher::CipherSuite;
+use packets::Packet;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Packet)]
+#[packet(id = 2)]
 #[repr(C)]
 pub struct InitAckPacket {
     pub public_key: [u8; 32],
+    pub connection_id: u64,
+    /// The cipher suite the server chose from those the client offered
+    pub cipher_suite: CipherSuite,
     nonce_response_1: [u8; 32],
     nonce_response_2: [u8; 32],
 }
 impl InitAckPacket {
-    pub fn new(remote: &Remote, nonce_response: &[u8])
+    pub fn new<C: Codec>(remote: &Remote<C>, cipher_suite: CipherSuite, nonce_response: &[u8])
                -> Result<InitAckPacket>
     {
         if remote.eph_private_key.is_none() {
@@ -28,6 +35,8 @@ impl InitAckPacket {
 
         Ok(InitAckPacket {
             public_key: public_key,
+            connection_id: remote.connection_id,
+            cipher_suite: cipher_suite,
             nonce_response_1: nonce_response_1,
             nonce_response_2: nonce_response_2,
         })
@@ -55,15 +64,19 @@ fn test() {
     enum Packet {
         InitAck(InitAckPacket),
     }
+    impl ::packets::Packet for Packet {
+        fn reply_expected(&self) -> bool { false }
+    }
 
     let remote_addr: SocketAddr = FromStr::from_str("0.0.0.0:0").unwrap();
     let mut remote = Remote::new(remote_addr, Arc::new(SystemRandom::new())).unwrap();
 
-    let init_ack_packet = InitAckPacket::new(&remote, &[99; 64]).unwrap();
+    let init_ack_packet = InitAckPacket::new(&remote, CipherSuite::Aes128Gcm, &[99; 64]).unwrap();
     let packet = Packet::InitAck(init_ack_packet.clone());
-    let mut bytes: Vec<u8> = remote.serialize_reply_packet(&packet, 177).unwrap();
-    let (packet2,_,stale) = remote.deserialize_packet(&mut bytes[..]).unwrap();
-    assert_eq!(stale,false);
+    let mut bytes: Vec<u8> = remote.serialize_reply_packet(&packet, 0xABCDE000, 1, 177).unwrap();
+    let (packet2,meta) = remote.deserialize_packet::<Packet>(&mut bytes[..]).unwrap();
+    assert!(!meta.stale);
+    assert_eq!(meta.in_reply_to,Some(177));
     match packet2 {
         Packet::InitAck(init_ack_packet2) => {
             assert_eq!(init_ack_packet2, init_ack_packet);
@@ -19,14 +19,122 @@ mod shutdown_complete;
 pub use self::shutdown_complete::ShutdownCompletePacket;
 mod upgrade_required;
 pub use self::upgrade_required::UpgradeRequiredPacket;
+mod connect;
+pub use self::connect::{ConnectPacket, ClientAuth};
+mod retry;
+mod path_challenge;
+pub use self::path_challenge::PathChallengePacket;
+mod path_response;
+pub use self::path_response::PathResponsePacket;
+pub use self::retry::RetryPacket;
+mod rekey;
+pub use self::rekey::RekeyPacket;
+mod rekey_ack;
+pub use self::rekey_ack::RekeyAckPacket;
+mod envelope;
+pub use self::envelope::{ControlPacket, Envelope, MessageId, MessageSet, check_message_set};
 
 // The maximum size of a packet, according to the protocol.
 pub const MAX_PROTO_PACKET: usize = 1500;
 
+// Every datagram starts with a cleartext prefix: the magic and version (4
+// bytes), the connection id (8 bytes) and the AEAD nonce (12 bytes).  The magic,
+// version and connection id are authenticated as associated data.
+pub const CONNECTION_ID_OFFSET: usize = 4;
+pub const NONCE_OFFSET: usize = 12;
+pub const PREFIX_SIZE: usize = 24;
+
+// Until a remote's address is validated, we send it at most this many times
+// the number of bytes we have received from it.
+pub const AMPLIFICATION_FACTOR: usize = 3;
+
 use errors::*;
+use cipher::CipherSuite;
+
+pub use siege_net_derive::{Packet, MessageSet};
+
+/// How the application wants a message delivered.  This is advice to whatever
+/// sends it; the protocol itself delivers every packet at most once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reliability {
+    /// May be lost, duplicated or reordered
+    Unreliable,
+    /// May be lost, but older messages arriving after newer ones are dropped
+    UnreliableSequenced,
+    /// Resent until acknowledged, in any order
+    Reliable,
+    /// Resent until acknowledged, and delivered in order within its channel
+    ReliableOrdered,
+}
 
+/// A message that can be sent with `Remote::serialize_packet()`.  Usually
+/// implemented with `#[derive(Packet)]` (see the `siege-net-derive` crate).
 pub trait Packet {
     fn reply_expected(&self) -> bool;
+
+    /// A stable number identifying the kind of message.  Ids below
+    /// `MIN_APPLICATION_MESSAGE_ID` are used by siege-net itself.
+    fn message_id(&self) -> u16 { 0 }
+
+    /// The channel the message belongs to.  Ordering (see `Reliability`) is
+    /// only kept within a channel.
+    fn channel(&self) -> u8 { 0 }
+
+    fn reliability(&self) -> Reliability { Reliability::Unreliable }
+}
+
+// Message ids below this are reserved for siege-net's own packets
+pub const MIN_APPLICATION_MESSAGE_ID: u16 = 256;
+
+// Returns the connection id of a datagram, which identifies the session it
+// belongs to regardless of the address it came from.  Zero means the sender has
+// not been assigned a connection id yet (it is still in the handshake).
+pub fn peek_connection_id(bytes: &[u8]) -> Result<u64>
+{
+    use bincode::deserialize;
+
+    if bytes.len() < PREFIX_SIZE { return Err(ErrorKind::InvalidPacket.into()); }
+    let connection_id: u64 = deserialize(&bytes[CONNECTION_ID_OFFSET..NONCE_OFFSET])?;
+    Ok(connection_id)
+}
+
+// The bytes the server signs to prove its identity during the handshake.  This
+// binds both ephemeral public keys, the client's challenge nonce, the cipher
+// suites offered and chosen, and the protocol version together, so none of them
+// can be swapped (or a weaker suite forced) by an attacker.
+pub fn handshake_transcript(
+    version: u32,
+    client_public_key: &[u8; 32],
+    server_public_key: &[u8; 32],
+    nonce: &[u8; 12],
+    cipher_suites: &[CipherSuite],
+    cipher_suite: CipherSuite) -> Vec<u8>
+{
+    const LABEL: &[u8] = b"siege-net handshake";
+    let mut transcript: Vec<u8> = Vec::with_capacity(
+        LABEL.len() + 4 + 32 + 32 + 12 + 1 + cipher_suites.len() + 1);
+    transcript.extend_from_slice(LABEL);
+    transcript.extend_from_slice(&[version as u8, (version >> 8) as u8,
+                                   (version >> 16) as u8, (version >> 24) as u8]);
+    transcript.extend_from_slice(&client_public_key[..]);
+    transcript.extend_from_slice(&server_public_key[..]);
+    transcript.extend_from_slice(&nonce[..]);
+    transcript.push(cipher_suites.len() as u8);
+    transcript.extend(cipher_suites.iter().map(|suite| *suite as u8));
+    transcript.push(cipher_suite as u8);
+    transcript
+}
+
+// The bytes a client with an identity key signs to prove its identity.  This
+// covers the whole handshake transcript, so it cannot be replayed in another
+// session.
+pub fn client_auth_transcript(handshake_transcript: &[u8]) -> Vec<u8>
+{
+    const LABEL: &[u8] = b"siege-net client auth";
+    let mut transcript: Vec<u8> = Vec::with_capacity(LABEL.len() + handshake_transcript.len());
+    transcript.extend_from_slice(LABEL);
+    transcript.extend_from_slice(handshake_transcript);
+    transcript
 }
 
 // Returns Ok(true) if correct version, Ok(false) if wrong version, Err(_) if
@@ -65,44 +173,86 @@ fn test() {
     use ring::rand::SystemRandom;
     use remote::Remote;
 
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    enum Packet {
+        InitAck(InitAckPacket),
+    }
+    impl self::Packet for Packet {
+        fn reply_expected(&self) -> bool { false }
+    }
+
     let remote_addr: SocketAddr = FromStr::from_str("127.0.0.1:4444").unwrap();
     let mut remote = Remote::new(remote_addr, Arc::new(SystemRandom::new())).unwrap();
 
-    let init_ack_packet = InitAckPacket::new(&remote, &[0; 64]).unwrap();
+    let init_ack_packet = InitAckPacket::new(&remote, CipherSuite::Aes128Gcm, &[0; 64]).unwrap();
     let packet = Packet::InitAck(init_ack_packet.clone());
-    let mut bytes: Vec<u8> = remote.serialize_packet(&packet).unwrap();
-    let (packet2,_,stale) = remote.deserialize_packet(&mut bytes[..]).unwrap();
-    assert_eq!(stale,false);
+    let mut bytes: Vec<u8> = remote.serialize_packet(&packet, 0xABCDE000, 1).unwrap();
+    let mut replay: Vec<u8> = bytes.clone();
+    let (packet2,meta) = remote.deserialize_packet::<Packet>(&mut bytes[..]).unwrap();
+    assert!(!meta.stale);
+    let (_,meta) = remote.deserialize_packet::<Packet>(&mut replay[..]).unwrap();
+    assert!(meta.stale);
     match packet2 {
         Packet::InitAck(init_ack_packet2) => {
             assert_eq!(init_ack_packet, init_ack_packet2);
         },
-        _ => panic!("Ser/De failed for InitAckPacket"),
     }
 }
 
 #[test]
 fn test_validate_magic_and_version() {
-    use bincode::Infinite;
-
-    let mav = 0xFF00 | 0x18;
-    let bytes: Vec<u8> = ::bincode::serialize(&mav, Infinite).unwrap();
-    match validate_magic_and_version(&*bytes, 0xFF00, 0x18) {
+    let mav: u32 = 0xABCDE000 | 0x18;
+    let bytes: Vec<u8> = ::bincode::serialize(&mav).unwrap();
+    match validate_magic_and_version(0xABCDE000, 0x18, &*bytes) {
         Ok(true) => {},
         _ => panic!("validate_magic_and_version() failed on valid input"),
     }
 
-    let mav = 0xFF00 | 254_u32;
-    let bytes: Vec<u8> = ::bincode::serialize(&mav, Infinite).unwrap();
-    match validate_magic_and_version(&*bytes, 0xFF00, 0x18) {
+    let mav = 0xABCDE000 | 254_u32;
+    let bytes: Vec<u8> = ::bincode::serialize(&mav).unwrap();
+    match validate_magic_and_version(0xABCDE000, 0x18, &*bytes) {
         Ok(false) => {},
         _ => panic!("validate_magic_and_version() yielded wrong result on bad version"),
     }
 
-    let mav = 0;
-    let bytes: Vec<u8> = ::bincode::serialize(&mav, Infinite).unwrap();
-    match validate_magic_and_version(&*bytes, 0xFF00, 0x18) {
+    let mav: u32 = 0;
+    let bytes: Vec<u8> = ::bincode::serialize(&mav).unwrap();
+    match validate_magic_and_version(0xABCDE000, 0x18, &*bytes) {
         Err(_) => {},
         _ => panic!("validate_magic_and_version() did not fail on bad packet"),
     }
 }
+
+#[test]
+fn test_derive_packet() {
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    #[packet(id = 300, channel = 2, reliability = "reliable_ordered")]
+    struct ChatPacket {
+        text: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    enum Message {
+        Heartbeat(HeartbeatPacket),
+        HeartbeatAck(HeartbeatAckPacket),
+        Chat(ChatPacket),
+    }
+
+    let heartbeat = HeartbeatPacket::new();
+    assert!(heartbeat.reply_expected());
+    assert_eq!(heartbeat.message_id(), 3);
+    assert_eq!(heartbeat.reliability(), Reliability::Unreliable);
+
+    let chat = ChatPacket { text: "hello".to_owned() };
+    assert!(!chat.reply_expected());
+    assert_eq!(chat.message_id(), 300);
+    assert_eq!(chat.channel(), 2);
+    assert_eq!(chat.reliability(), Reliability::ReliableOrdered);
+
+    // Enums delegate to the packet they wrap
+    assert!(Message::Heartbeat(heartbeat).reply_expected());
+    assert_eq!(Message::HeartbeatAck(HeartbeatAckPacket::new()).message_id(), 4);
+    let message = Message::Chat(chat);
+    assert_eq!(message.message_id(), 300);
+    assert_eq!(message.channel(), 2);
+}
@@ -0,0 +1,18 @@
+
+use packets::Packet;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Packet)]
+#[packet(id = 10, reply_expected)]
+#[repr(C)]
+pub struct PathChallengePacket {
+    pub data: [u8; 8],
+}
+
+impl PathChallengePacket {
+    pub fn new(data: [u8; 8]) -> PathChallengePacket
+    {
+        PathChallengePacket {
+            data: data
+        }
+    }
+}
@@ -0,0 +1,18 @@
+
+use packets::{Packet, PathChallengePacket};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Packet)]
+#[packet(id = 11)]
+#[repr(C)]
+pub struct PathResponsePacket {
+    pub data: [u8; 8],
+}
+
+impl PathResponsePacket {
+    pub fn new(challenge: &PathChallengePacket) -> PathResponsePacket
+    {
+        PathResponsePacket {
+            data: challenge.data
+        }
+    }
+}
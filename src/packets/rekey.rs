@@ -0,0 +1,20 @@
+
+use packets::Packet;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Packet)]
+#[packet(id = 12, reply_expected)]
+#[repr(C)]
+pub struct RekeyPacket {
+    pub epoch: u32,
+    pub public_key: [u8; 32],
+}
+
+impl RekeyPacket {
+    pub fn new(epoch: u32, public_key: [u8; 32]) -> RekeyPacket
+    {
+        RekeyPacket {
+            epoch: epoch,
+            public_key: public_key,
+        }
+    }
+}
@@ -0,0 +1,20 @@
+
+use packets::Packet;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Packet)]
+#[packet(id = 13)]
+#[repr(C)]
+pub struct RekeyAckPacket {
+    pub epoch: u32,
+    pub public_key: [u8; 32],
+}
+
+impl RekeyAckPacket {
+    pub fn new(epoch: u32, public_key: [u8; 32]) -> RekeyAckPacket
+    {
+        RekeyAckPacket {
+            epoch: epoch,
+            public_key: public_key,
+        }
+    }
+}
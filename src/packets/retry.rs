@@ -0,0 +1,19 @@
+
+use packets::Packet;
+use cookie::Cookie;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Packet)]
+#[packet(id = 9)]
+#[repr(C)]
+pub struct RetryPacket {
+    pub cookie: Cookie,
+}
+
+impl RetryPacket {
+    pub fn new(cookie: Cookie) -> RetryPacket
+    {
+        RetryPacket {
+            cookie: cookie
+        }
+    }
+}
@@ -0,0 +1,211 @@
+
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use packets::MAX_PROTO_PACKET;
+
+/// Counters for a `BufferPool`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Number of buffers in the pool
+    pub capacity: usize,
+    /// Buffers currently handed out
+    pub in_use: usize,
+    /// Buffers handed out since the pool was created
+    pub acquired: usize,
+    /// Times a buffer was asked for while all of them were in use
+    pub exhausted: usize,
+}
+
+struct Slot {
+    in_use: AtomicBool,
+    buf: UnsafeCell<[u8; MAX_PROTO_PACKET]>,
+}
+
+// A slot's buffer is only touched through the one `PooledBuffer` which set
+// its `in_use` flag.
+unsafe impl Sync for Slot {}
+
+struct Inner {
+    slots: Vec<Slot>,
+    next: AtomicUsize,
+    in_use: AtomicUsize,
+    acquired: AtomicUsize,
+    exhausted: AtomicUsize,
+}
+
+/// A fixed set of `MAX_PROTO_PACKET` sized datagram buffers, which can be
+/// shared (it is cheap to clone) between the socket loop and whatever
+/// serializes packets (see `Remote::serialize_packet_pooled()`).  Taking and
+/// returning a buffer is lock-free.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<Inner>,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> BufferPool
+    {
+        BufferPool {
+            inner: Arc::new(Inner {
+                slots: (0..capacity).map(|_| Slot {
+                    in_use: AtomicBool::new(false),
+                    buf: UnsafeCell::new([0; MAX_PROTO_PACKET]),
+                }).collect(),
+                next: AtomicUsize::new(0),
+                in_use: AtomicUsize::new(0),
+                acquired: AtomicUsize::new(0),
+                exhausted: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    /// Take a buffer from the pool, or `None` if all of them are in use.  The
+    /// buffer goes back to the pool when the handle is dropped.  Its contents
+    /// are whatever its last user left there.
+    pub fn acquire(&self) -> Option<PooledBuffer>
+    {
+        let inner = &self.inner;
+        let capacity = inner.slots.len();
+        // Start where the last search left off, so that a busy pool is not
+        // always scanned from the beginning
+        let start = inner.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..capacity {
+            let index = (start + i) % capacity;
+            let slot = &inner.slots[index];
+            if slot.in_use.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                inner.in_use.fetch_add(1, Ordering::Relaxed);
+                inner.acquired.fetch_add(1, Ordering::Relaxed);
+                return Some(PooledBuffer {
+                    pool: inner.clone(),
+                    index: index,
+                    len: MAX_PROTO_PACKET,
+                });
+            }
+        }
+        inner.exhausted.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    pub fn stats(&self) -> PoolStats
+    {
+        PoolStats {
+            capacity: self.inner.slots.len(),
+            in_use: self.inner.in_use.load(Ordering::Relaxed),
+            acquired: self.inner.acquired.load(Ordering::Relaxed),
+            exhausted: self.inner.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A buffer taken from a `BufferPool`.  It dereferences to its first `len()`
+/// bytes, which is the whole buffer until `set_len()` is called (for example
+/// with the size of a datagram received into it).
+pub struct PooledBuffer {
+    pool: Arc<Inner>,
+    index: usize,
+    len: usize,
+}
+
+impl PooledBuffer {
+    pub fn len(&self) -> usize
+    {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len == 0
+    }
+
+    /// Set the length of the buffer, at most `MAX_PROTO_PACKET`
+    pub fn set_len(&mut self, len: usize)
+    {
+        assert!(len <= MAX_PROTO_PACKET);
+        self.len = len;
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8]
+    {
+        let buf = unsafe { &*self.pool.slots[self.index].buf.get() };
+        &buf[..self.len]
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8]
+    {
+        let buf = unsafe { &mut *self.pool.slots[self.index].buf.get() };
+        &mut buf[..self.len]
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self)
+    {
+        self.pool.in_use.fetch_sub(1, Ordering::Relaxed);
+        self.pool.slots[self.index].in_use.store(false, Ordering::Release);
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use std::net::SocketAddr;
+    use std::thread;
+    use ring::rand::SystemRandom;
+    use remote::Remote;
+    use packets::HeartbeatPacket;
+
+    let pool = BufferPool::new(2);
+    let mut a = pool.acquire().unwrap();
+    let b = pool.acquire().unwrap();
+    assert!(pool.acquire().is_none());
+    assert_eq!(pool.stats(), PoolStats { capacity: 2, in_use: 2, acquired: 2, exhausted: 1 });
+
+    a[0] = 42;
+    a.set_len(1);
+    assert_eq!(&a[..], &[42]);
+    drop(a);
+    drop(b);
+    assert_eq!(pool.stats().in_use, 0);
+
+    // Serializing into a pooled buffer, and receiving into another
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng).unwrap();
+    let packet = HeartbeatPacket::new();
+    let sent = client.serialize_packet_pooled(&packet, 0xABCDE000, 1, &pool).unwrap();
+    let mut received = pool.acquire().unwrap();
+    received[..sent.len()].copy_from_slice(&sent);
+    received.set_len(sent.len());
+    drop(sent);
+    let (body, _, _) = server.deserialize_packet_header::<HeartbeatPacket>(&mut received[..])
+        .unwrap();
+    assert_eq!(::bincode::deserialize::<HeartbeatPacket>(body).unwrap(), packet);
+
+    // Buffers come back from other threads
+    let threads: Vec<_> = (0..4).map(|_| {
+        let pool = pool.clone();
+        thread::spawn(move || {
+            for _ in 0..1000 {
+                if let Some(mut buf) = pool.acquire() {
+                    buf[0] = 1;
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    drop(received);
+    assert_eq!(pool.stats().in_use, 0);
+}
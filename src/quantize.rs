@@ -0,0 +1,477 @@
+
+use std::fmt;
+use std::marker::PhantomData;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use errors::*;
+use bits::{BitEncode, BitWriter, BitReader, serialize_bits, deserialize_bits};
+
+/// The step (out of `2^bits - 1`) nearest to `value` clamped to `min..=max`
+pub fn quantize(value: f32, min: f32, max: f32, bits: u32) -> u64
+{
+    assert!(bits <= 32 && min < max);
+    let steps = ((1_u64 << bits) - 1) as f64;
+    let value = if value.is_nan() { min } else { value.max(min).min(max) };
+    ((value - min) as f64 / (max - min) as f64 * steps).round() as u64
+}
+
+/// The value of a step returned by `quantize()`
+pub fn dequantize(step: u64, min: f32, max: f32, bits: u32) -> f32
+{
+    assert!(bits <= 32 && min < max);
+    let steps = ((1_u64 << bits) - 1) as f64;
+    (min as f64 + step.min(steps as u64) as f64 / steps * (max - min) as f64) as f32
+}
+
+/// The range and precision of a `Quantized` value
+pub trait QuantizeRange {
+    const MIN: f32;
+    const MAX: f32;
+    const BITS: u32;
+}
+
+/// A float which is sent as a fixed-point number within `R`'s range.  Values
+/// outside the range are clamped.
+///
+/// ```ignore
+/// struct WorldAxis;
+/// impl QuantizeRange for WorldAxis {
+///     const MIN: f32 = -4096.0;
+///     const MAX: f32 = 4096.0;
+///     const BITS: u32 = 20; // better than 1cm
+/// }
+/// ```
+pub struct Quantized<R> {
+    pub value: f32,
+    range: PhantomData<R>,
+}
+
+impl<R: QuantizeRange> Quantized<R> {
+    pub fn new(value: f32) -> Quantized<R>
+    {
+        Quantized { value: value, range: PhantomData }
+    }
+
+    /// The value as the receiver will see it
+    pub fn quantized(&self) -> f32
+    {
+        dequantize(self.step(), R::MIN, R::MAX, R::BITS)
+    }
+
+    fn step(&self) -> u64
+    {
+        quantize(self.value, R::MIN, R::MAX, R::BITS)
+    }
+}
+
+// Not derived, as that would require `R` to implement these too
+impl<R> Clone for Quantized<R> {
+    fn clone(&self) -> Quantized<R> { *self }
+}
+impl<R> Copy for Quantized<R> {}
+impl<R> PartialEq for Quantized<R> {
+    fn eq(&self, other: &Quantized<R>) -> bool { self.value == other.value }
+}
+impl<R> fmt::Debug for Quantized<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Quantized({})", self.value) }
+}
+
+impl<R: QuantizeRange> Serialize for Quantized<R> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    {
+        serialize_bits(self.step(), R::BITS, serializer)
+    }
+}
+
+impl<'de, R: QuantizeRange> Deserialize<'de> for Quantized<R> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+                                         -> ::std::result::Result<Quantized<R>, D::Error>
+    {
+        let step = deserialize_bits(R::BITS, deserializer)?;
+        Ok(Quantized::new(dequantize(step, R::MIN, R::MAX, R::BITS)))
+    }
+}
+
+impl<R: QuantizeRange> BitEncode for Quantized<R> {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()>
+    {
+        writer.write_bits(self.step(), R::BITS)
+    }
+
+    fn decode(reader: &mut BitReader) -> Result<Quantized<R>>
+    {
+        let step = reader.read_bits(R::BITS)?;
+        Ok(Quantized::new(dequantize(step, R::MIN, R::MAX, R::BITS)))
+    }
+}
+
+/// A position (or any vector) with each axis quantized to `R`
+pub struct QuantizedVec3<R> {
+    pub x: Quantized<R>,
+    pub y: Quantized<R>,
+    pub z: Quantized<R>,
+}
+
+impl<R: QuantizeRange> QuantizedVec3<R> {
+    pub fn new(v: [f32; 3]) -> QuantizedVec3<R>
+    {
+        QuantizedVec3 { x: Quantized::new(v[0]), y: Quantized::new(v[1]), z: Quantized::new(v[2]) }
+    }
+
+    pub fn get(&self) -> [f32; 3]
+    {
+        [self.x.value, self.y.value, self.z.value]
+    }
+}
+
+impl<R> Clone for QuantizedVec3<R> {
+    fn clone(&self) -> QuantizedVec3<R> { *self }
+}
+impl<R> Copy for QuantizedVec3<R> {}
+impl<R> PartialEq for QuantizedVec3<R> {
+    fn eq(&self, other: &QuantizedVec3<R>) -> bool
+    {
+        self.x == other.x && self.y == other.y && self.z == other.z
+    }
+}
+impl<R> fmt::Debug for QuantizedVec3<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "QuantizedVec3({}, {}, {})", self.x.value, self.y.value, self.z.value)
+    }
+}
+
+impl<R: QuantizeRange> Serialize for QuantizedVec3<R> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    {
+        (self.x, self.y, self.z).serialize(serializer)
+    }
+}
+
+impl<'de, R: QuantizeRange> Deserialize<'de> for QuantizedVec3<R> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+                                         -> ::std::result::Result<QuantizedVec3<R>, D::Error>
+    {
+        let (x, y, z) = Deserialize::deserialize(deserializer)?;
+        Ok(QuantizedVec3 { x: x, y: y, z: z })
+    }
+}
+
+impl<R: QuantizeRange> BitEncode for QuantizedVec3<R> {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()>
+    {
+        self.x.encode(writer)?;
+        self.y.encode(writer)?;
+        self.z.encode(writer)
+    }
+
+    fn decode(reader: &mut BitReader) -> Result<QuantizedVec3<R>>
+    {
+        Ok(QuantizedVec3 {
+            x: Quantized::decode(reader)?,
+            y: Quantized::decode(reader)?,
+            z: Quantized::decode(reader)?,
+        })
+    }
+}
+
+/// Bits for each of the three smallest components of a `Rotation`
+pub const ROTATION_COMPONENT_BITS: u32 = 10;
+
+// The three smallest components of a unit quaternion are within this of zero
+const SMALLEST_THREE_BOUND: f32 = ::std::f32::consts::FRAC_1_SQRT_2;
+
+/// A unit quaternion `[x, y, z, w]`, sent in 32 bits with the "smallest
+/// three" encoding: the index of the largest component in 2 bits, then the
+/// other three in `ROTATION_COMPONENT_BITS` each.  The largest is recovered
+/// from the others, as the quaternion has unit length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rotation(pub [f32; 4]);
+
+impl Rotation {
+    fn pack(&self) -> u64
+    {
+        let q = self.0;
+        let mut largest = 0;
+        for i in 1..4 {
+            if q[i].abs() > q[largest].abs() { largest = i; }
+        }
+        // q and -q are the same rotation, so make the largest positive
+        let sign = if q[largest] < 0.0 { -1.0 } else { 1.0 };
+
+        let mut packed = largest as u64;
+        let mut shift = 2;
+        for (i, component) in q.iter().enumerate() {
+            if i == largest { continue; }
+            let step = quantize(component * sign, -SMALLEST_THREE_BOUND, SMALLEST_THREE_BOUND,
+                                ROTATION_COMPONENT_BITS);
+            packed |= step << shift;
+            shift += ROTATION_COMPONENT_BITS;
+        }
+        packed
+    }
+
+    fn unpack(packed: u64) -> Rotation
+    {
+        let largest = (packed & 3) as usize;
+        let mut q = [0.0_f32; 4];
+        let mut shift = 2;
+        let mut sum = 0.0;
+        for (i, component) in q.iter_mut().enumerate() {
+            if i == largest { continue; }
+            let step = (packed >> shift) & ((1 << ROTATION_COMPONENT_BITS) - 1);
+            *component = dequantize(step, -SMALLEST_THREE_BOUND, SMALLEST_THREE_BOUND,
+                                    ROTATION_COMPONENT_BITS);
+            sum += *component * *component;
+            shift += ROTATION_COMPONENT_BITS;
+        }
+        q[largest] = (1.0 - sum).max(0.0).sqrt();
+        Rotation(q)
+    }
+
+    /// The rotation as the receiver will see it
+    pub fn quantized(&self) -> Rotation
+    {
+        Rotation::unpack(self.pack())
+    }
+}
+
+impl Serialize for Rotation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    {
+        serialize_bits(self.pack(), 2 + 3 * ROTATION_COMPONENT_BITS, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rotation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+                                         -> ::std::result::Result<Rotation, D::Error>
+    {
+        deserialize_bits(2 + 3 * ROTATION_COMPONENT_BITS, deserializer).map(Rotation::unpack)
+    }
+}
+
+impl BitEncode for Rotation {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()>
+    {
+        writer.write_bits(self.pack(), 2 + 3 * ROTATION_COMPONENT_BITS)
+    }
+
+    fn decode(reader: &mut BitReader) -> Result<Rotation>
+    {
+        Ok(Rotation::unpack(reader.read_bits(2 + 3 * ROTATION_COMPONENT_BITS)?))
+    }
+}
+
+/// Convert to an IEEE 754 half precision float, rounding to nearest even.
+/// Values too large for it become infinity.
+pub fn f32_to_half(value: f32) -> u16
+{
+    let x = value.to_bits();
+    let sign = ((x >> 16) & 0x8000) as u16;
+    let exp = ((x >> 23) & 0xFF) as i32;
+    let man = x & 0x7F_FFFF;
+
+    if exp == 0xFF {
+        // Infinity, or NaN (kept quiet)
+        return sign | 0x7C00 | if man != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1F {
+        return sign | 0x7C00;
+    }
+
+    let (half, rem, halfway) = if e <= 0 {
+        // Subnormal, or too small for even that
+        if e < -10 { return sign; }
+        let man = man | 0x80_0000;
+        let shift = (14 - e) as u32;
+        (man >> shift, man & ((1 << shift) - 1), 1 << (shift - 1))
+    } else {
+        (((e as u32) << 10) | (man >> 13), man & 0x1FFF, 0x1000)
+    };
+    // A carry out of the mantissa correctly bumps the exponent
+    let round = rem > halfway || (rem == halfway && half & 1 == 1);
+    sign | (half + round as u32) as u16
+}
+
+/// Convert from an IEEE 754 half precision float
+pub fn half_to_f32(half: u16) -> f32
+{
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exp = ((half >> 10) & 0x1F) as u32;
+    let man = (half & 0x3FF) as u32;
+
+    let bits = match exp {
+        0 if man == 0 => sign,
+        0 => {
+            // Subnormal, which is normal as an f32
+            let mut exp = 113;
+            let mut man = man;
+            while man & 0x400 == 0 {
+                man <<= 1;
+                exp -= 1;
+            }
+            sign | (exp << 23) | ((man & 0x3FF) << 13)
+        },
+        0x1F => sign | 0x7F80_0000 | (man << 13),
+        _ => sign | ((exp + 112) << 23) | (man << 13),
+    };
+    f32::from_bits(bits)
+}
+
+/// A float sent in half precision: 16 bits, with about 3 significant digits
+/// across a wide range.  Good for velocities and other values without fixed
+/// bounds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Half(pub f32);
+
+impl Half {
+    /// The value as the receiver will see it
+    pub fn quantized(&self) -> f32
+    {
+        half_to_f32(f32_to_half(self.0))
+    }
+}
+
+impl Serialize for Half {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    {
+        serialize_bits(f32_to_half(self.0) as u64, 16, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Half {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+                                         -> ::std::result::Result<Half, D::Error>
+    {
+        deserialize_bits(16, deserializer).map(|half| Half(half_to_f32(half as u16)))
+    }
+}
+
+impl BitEncode for Half {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()>
+    {
+        writer.write_bits(f32_to_half(self.0) as u64, 16)
+    }
+
+    fn decode(reader: &mut BitReader) -> Result<Half>
+    {
+        Ok(Half(half_to_f32(reader.read_bits(16)? as u16)))
+    }
+}
+
+/// A vector sent in half precision
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HalfVec3(pub [f32; 3]);
+
+impl Serialize for HalfVec3 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+    {
+        (Half(self.0[0]), Half(self.0[1]), Half(self.0[2])).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HalfVec3 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+                                         -> ::std::result::Result<HalfVec3, D::Error>
+    {
+        let (x, y, z): (Half, Half, Half) = Deserialize::deserialize(deserializer)?;
+        Ok(HalfVec3([x.0, y.0, z.0]))
+    }
+}
+
+impl BitEncode for HalfVec3 {
+    fn encode(&self, writer: &mut BitWriter) -> Result<()>
+    {
+        for v in &self.0 {
+            Half(*v).encode(writer)?;
+        }
+        Ok(())
+    }
+
+    fn decode(reader: &mut BitReader) -> Result<HalfVec3>
+    {
+        Ok(HalfVec3([Half::decode(reader)?.0, Half::decode(reader)?.0, Half::decode(reader)?.0]))
+    }
+}
+
+#[test]
+fn test() {
+    use codec::{Codec, BincodeCodec};
+    use bitcodec::BitCodec;
+
+    struct WorldAxis;
+    impl QuantizeRange for WorldAxis {
+        const MIN: f32 = -4096.0;
+        const MAX: f32 = 4096.0;
+        const BITS: u32 = 20;
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Transform {
+        position: QuantizedVec3<WorldAxis>,
+        rotation: Rotation,
+        velocity: HalfVec3,
+    }
+
+    // Fixed point
+    let x: Quantized<WorldAxis> = Quantized::new(1234.5678);
+    assert!((x.quantized() - 1234.5678).abs() < 8192.0 / ((1 << 20) - 1) as f32);
+    assert_eq!(Quantized::<WorldAxis>::new(1.0e9).quantized(), 4096.0);
+    assert_eq!(Quantized::<WorldAxis>::new(-4096.0).quantized(), -4096.0);
+
+    // Smallest three
+    let half_turn = (0.5_f32).sqrt();
+    for q in &[[0.0, 0.0, 0.0, 1.0], [half_turn, 0.0, 0.0, -half_turn],
+               [0.1825742, 0.3651484, 0.5477226, -0.7302967]] {
+        let r = Rotation(*q).quantized();
+        // The same rotation, up to sign
+        let dot: f32 = (0..4).map(|i| q[i] * r.0[i]).sum();
+        assert!(dot.abs() > 0.9999, "{:?} became {:?}", q, r);
+    }
+
+    // Half precision
+    assert_eq!(f32_to_half(0.0), 0);
+    assert_eq!(f32_to_half(-0.0), 0x8000);
+    assert_eq!(f32_to_half(1.0), 0x3C00);
+    assert_eq!(f32_to_half(-2.0), 0xC000);
+    assert_eq!(f32_to_half(65504.0), 0x7BFF);
+    assert_eq!(f32_to_half(70000.0), 0x7C00);
+    assert_eq!(f32_to_half(f32::NEG_INFINITY), 0xFC00);
+    assert_eq!(f32_to_half(5.960464e-8), 1); // the smallest subnormal
+    assert_eq!(f32_to_half(1.0e-8), 0);
+    assert_eq!(f32_to_half(1.0 + 1.0 / 2048.0), 0x3C00); // a tie, to even
+    assert_eq!(f32_to_half(1.0 + 3.0 / 2048.0), 0x3C02);
+    assert!(half_to_f32(f32_to_half(f32::NAN)).is_nan());
+    for h in 0..0x7C00_u16 {
+        assert_eq!(f32_to_half(half_to_f32(h)), h);
+    }
+    assert!((Half(1234.5).quantized() - 1234.5).abs() < 1.0);
+
+    // On the wire
+    let transform = Transform {
+        position: QuantizedVec3::new([100.25, -2000.0, 7.5]),
+        rotation: Rotation([0.0, 0.0, half_turn, half_turn]),
+        velocity: HalfVec3([1.5, 0.0, -9.75]),
+    };
+    let size = BitCodec::serialized_size(&transform).unwrap();
+    assert_eq!(size, 18); // 140 bits
+    assert!(size < BincodeCodec::serialized_size(&transform).unwrap());
+    let mut buf = [0; 64];
+    BitCodec::serialize_into(&transform, &mut buf).unwrap();
+    let received = BitCodec::deserialize::<Transform>(&buf[..size]).unwrap();
+    assert_eq!(received.velocity, transform.velocity);
+    for i in 0..3 {
+        assert!((received.position.get()[i] - transform.position.get()[i]).abs() < 0.01);
+    }
+    assert_eq!(received.rotation, transform.rotation.quantized());
+
+    // And through BitEncode
+    let mut writer = BitWriter::new(&mut buf);
+    transform.position.encode(&mut writer).unwrap();
+    transform.rotation.encode(&mut writer).unwrap();
+    assert_eq!(writer.bits_written(), 3 * 20 + 32);
+    let mut reader = BitReader::new(&buf);
+    assert_eq!(QuantizedVec3::<WorldAxis>::decode(&mut reader).unwrap(), received.position);
+    assert_eq!(Rotation::decode(&mut reader).unwrap(), received.rotation);
+}
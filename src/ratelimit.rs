@@ -0,0 +1,375 @@
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Limits applied to each source, before any decryption is attempted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitConfig {
+    /// Sustained packets per second allowed from one source
+    pub packets_per_second: f64,
+
+    /// Packets a source may send in a burst above the sustained rate
+    pub burst: f64,
+
+    /// Sources are IPv4 addresses masked to this many bits (32 means each
+    /// address is its own source, 24 groups a /24 subnet together)
+    pub ipv4_prefix: u8,
+
+    /// Sources are IPv6 addresses masked to this many bits
+    pub ipv6_prefix: u8,
+
+    /// Ban a source after this many packets that failed to decrypt...
+    pub max_decrypt_failures: u32,
+
+    /// ...within this period
+    pub failure_window: Duration,
+
+    /// Ban a source after this many packets over its rate limit in a row
+    pub max_violations: u32,
+
+    /// How long automatic bans last
+    pub ban_duration: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            packets_per_second: 100.0,
+            burst: 200.0,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            max_decrypt_failures: 20,
+            failure_window: Duration::from_secs(10),
+            max_violations: 1000,
+            ban_duration: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// What to do with a packet from some source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Process the packet
+    Allow,
+    /// Drop the packet, the source is over its rate limit
+    Limited,
+    /// Drop the packet, the source is temporarily banned
+    Banned,
+    /// Drop the packet, the source is on the deny list
+    Denied,
+}
+
+/// Totals across all sources
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimitStats {
+    pub allowed: u64,
+    pub limited: u64,
+    pub banned: u64,
+    pub denied: u64,
+    pub decrypt_failures: u64,
+    pub bans: u64,
+}
+
+struct Source {
+    tokens: f64,
+    last_refill: Instant,
+    violations: u32,
+    failures: u32,
+    failure_window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// A token-bucket rate limiter keyed by source IP address (or subnet), with
+/// automatic temporary bans and manual allow and deny lists.
+///
+/// Call `check()` for every datagram as it comes off the socket, and
+/// `record_decrypt_failure()` whenever a packet fails to open.
+pub struct RateLimiter {
+    pub config: RateLimitConfig,
+    pub stats: RateLimitStats,
+    sources: HashMap<IpAddr, Source>,
+    allow_list: HashSet<IpAddr>,
+    deny_list: HashSet<IpAddr>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter
+    {
+        RateLimiter {
+            config: config,
+            stats: Default::default(),
+            sources: HashMap::new(),
+            allow_list: HashSet::new(),
+            deny_list: HashSet::new(),
+        }
+    }
+
+    /// Decide what to do with a packet from `ip` arriving at `now`
+    pub fn check(&mut self, ip: IpAddr, now: Instant) -> Verdict
+    {
+        let verdict = self.verdict(ip, now);
+        match verdict {
+            Verdict::Allow => self.stats.allowed += 1,
+            Verdict::Limited => self.stats.limited += 1,
+            Verdict::Banned => self.stats.banned += 1,
+            Verdict::Denied => self.stats.denied += 1,
+        }
+        verdict
+    }
+
+    fn verdict(&mut self, ip: IpAddr, now: Instant) -> Verdict
+    {
+        if self.deny_list.contains(&ip) {
+            return Verdict::Denied;
+        }
+        if self.allow_list.contains(&ip) {
+            return Verdict::Allow;
+        }
+
+        let config = self.config;
+        let source = self.source(ip, now);
+
+        if source.is_banned(now) {
+            return Verdict::Banned;
+        }
+
+        // Refill the bucket
+        let elapsed = now.duration_since(source.last_refill).as_secs_f64();
+        source.tokens = (source.tokens + elapsed * config.packets_per_second).min(config.burst);
+        source.last_refill = now;
+
+        if source.tokens >= 1.0 {
+            source.tokens -= 1.0;
+            source.violations = 0;
+            return Verdict::Allow;
+        }
+
+        source.violations += 1;
+        if source.violations >= config.max_violations {
+            source.ban(now + config.ban_duration);
+            warn!("Banned {} for exceeding its rate limit", ip);
+            self.stats.bans += 1;
+        }
+        Verdict::Limited
+    }
+
+    /// Record that a packet from `ip` failed to decrypt (or was otherwise
+    /// invalid).  Too many of these bans the source.
+    pub fn record_decrypt_failure(&mut self, ip: IpAddr, now: Instant)
+    {
+        self.stats.decrypt_failures += 1;
+        if self.allow_list.contains(&ip) {
+            return;
+        }
+
+        let config = self.config;
+        let source = self.source(ip, now);
+        if now.duration_since(source.failure_window_start) > config.failure_window {
+            source.failure_window_start = now;
+            source.failures = 0;
+        }
+        source.failures += 1;
+        if source.failures >= config.max_decrypt_failures && !source.is_banned(now) {
+            source.ban(now + config.ban_duration);
+            warn!("Banned {} after {} packets failed to decrypt", ip, source.failures);
+            self.stats.bans += 1;
+        }
+    }
+
+    /// Number of decryption failures from the source of `ip` in the current window
+    pub fn decrypt_failures(&self, ip: IpAddr) -> u32
+    {
+        match self.sources.get(&self.source_key(ip)) {
+            Some(source) => source.failures,
+            None => 0,
+        }
+    }
+
+    /// Ban the source of `ip` for `duration` from `now`
+    pub fn ban(&mut self, ip: IpAddr, now: Instant, duration: Duration)
+    {
+        self.source(ip, now).ban(now + duration);
+        self.stats.bans += 1;
+    }
+
+    /// Lift any ban on the source of `ip`
+    pub fn unban(&mut self, ip: IpAddr)
+    {
+        let key = self.source_key(ip);
+        if let Some(source) = self.sources.get_mut(&key) {
+            source.banned_until = None;
+            source.violations = 0;
+            source.failures = 0;
+        }
+    }
+
+    /// Always allow packets from `ip`, without rate limiting
+    pub fn allow(&mut self, ip: IpAddr)
+    {
+        self.deny_list.remove(&ip);
+        self.allow_list.insert(ip);
+    }
+
+    /// Never allow packets from `ip`
+    pub fn deny(&mut self, ip: IpAddr)
+    {
+        self.allow_list.remove(&ip);
+        self.deny_list.insert(ip);
+    }
+
+    /// Remove `ip` from the allow and deny lists
+    pub fn clear(&mut self, ip: IpAddr)
+    {
+        self.allow_list.remove(&ip);
+        self.deny_list.remove(&ip);
+    }
+
+    /// Forget sources that are not banned and have been quiet long enough to
+    /// have a full bucket again.  Call this periodically.
+    pub fn purge(&mut self, now: Instant)
+    {
+        let config = self.config;
+        let refill = Duration::from_millis(
+            (config.burst / config.packets_per_second * 1000.0) as u64);
+        self.sources.retain(|_, source| {
+            source.is_banned(now) || now.duration_since(source.last_refill) < refill
+        });
+    }
+
+    fn source(&mut self, ip: IpAddr, now: Instant) -> &mut Source
+    {
+        let key = self.source_key(ip);
+        let burst = self.config.burst;
+        self.sources.entry(key).or_insert_with(|| Source {
+            tokens: burst,
+            last_refill: now,
+            violations: 0,
+            failures: 0,
+            failure_window_start: now,
+            banned_until: None,
+        })
+    }
+
+    // The address masked to the configured prefix
+    fn source_key(&self, ip: IpAddr) -> IpAddr
+    {
+        match ip {
+            IpAddr::V4(ip) => {
+                let bits = u32::from(ip);
+                let mask = prefix_mask(self.config.ipv4_prefix, 32) as u32;
+                IpAddr::V4((bits & mask).into())
+            },
+            IpAddr::V6(ip) => {
+                let mut octets = ip.octets();
+                let prefix = self.config.ipv6_prefix.min(128) as usize;
+                for (i, octet) in octets.iter_mut().enumerate() {
+                    let bits = prefix.saturating_sub(i * 8).min(8);
+                    *octet &= !(0xFF_u16 >> bits) as u8;
+                }
+                IpAddr::V6(octets.into())
+            },
+        }
+    }
+}
+
+impl Source {
+    fn is_banned(&mut self, now: Instant) -> bool
+    {
+        match self.banned_until {
+            Some(until) if now < until => true,
+            Some(_) => {
+                self.banned_until = None;
+                self.violations = 0;
+                self.failures = 0;
+                false
+            },
+            None => false,
+        }
+    }
+
+    fn ban(&mut self, until: Instant)
+    {
+        self.banned_until = Some(until);
+    }
+}
+
+fn prefix_mask(prefix: u8, width: u8) -> u64
+{
+    let prefix = prefix.min(width) as u32;
+    if prefix == 0 {
+        0
+    } else {
+        (!0_u64 << (width as u32 - prefix)) & (!0_u64 >> (64 - width as u32))
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+
+    let config = RateLimitConfig {
+        packets_per_second: 10.0,
+        burst: 5.0,
+        ipv4_prefix: 24,
+        max_decrypt_failures: 3,
+        max_violations: 10,
+        ..Default::default()
+    };
+    let mut limiter = RateLimiter::new(config);
+    let start = Instant::now();
+    let a: IpAddr = FromStr::from_str("10.1.2.3").unwrap();
+    let a_neighbour: IpAddr = FromStr::from_str("10.1.2.99").unwrap();
+    let b: IpAddr = FromStr::from_str("10.1.3.3").unwrap();
+
+    // The burst is allowed, then the subnet is limited
+    for _ in 0..5 {
+        assert_eq!(limiter.check(a, start), Verdict::Allow);
+    }
+    assert_eq!(limiter.check(a_neighbour, start), Verdict::Limited);
+    assert_eq!(limiter.check(b, start), Verdict::Allow);
+
+    // Tokens come back over time
+    let later = start + Duration::from_millis(100);
+    assert_eq!(limiter.check(a, later), Verdict::Allow);
+    assert_eq!(limiter.check(a, later), Verdict::Limited);
+
+    // Persistent flooding gets the source banned
+    for _ in 0..10 {
+        limiter.check(a, later);
+    }
+    assert_eq!(limiter.check(a, later + Duration::from_secs(1)), Verdict::Banned);
+    assert_eq!(limiter.stats.bans, 1);
+    let after_ban = later + config.ban_duration;
+    assert_eq!(limiter.check(a, after_ban), Verdict::Allow);
+
+    // Failed decryptions get the source banned too
+    for _ in 0..3 {
+        limiter.record_decrypt_failure(b, start);
+    }
+    assert_eq!(limiter.decrypt_failures(b), 3);
+    assert_eq!(limiter.check(b, start), Verdict::Banned);
+    limiter.unban(b);
+    assert_eq!(limiter.check(b, start), Verdict::Allow);
+
+    // Manual lists
+    limiter.deny(b);
+    assert_eq!(limiter.check(b, start), Verdict::Denied);
+    limiter.allow(a);
+    for _ in 0..100 {
+        assert_eq!(limiter.check(a, start), Verdict::Allow);
+    }
+    limiter.clear(a);
+    limiter.clear(b);
+
+    // IPv6 sources are grouped by prefix
+    let c: IpAddr = FromStr::from_str("2001:db8:1:2:3:4:5:6").unwrap();
+    let c_neighbour: IpAddr = FromStr::from_str("2001:db8:1:2:ffff::1").unwrap();
+    for _ in 0..5 {
+        assert_eq!(limiter.check(c, start), Verdict::Allow);
+    }
+    assert_eq!(limiter.check(c_neighbour, start), Verdict::Limited);
+
+    limiter.purge(after_ban + Duration::from_secs(60));
+    assert!(limiter.sources.is_empty());
+}
@@ -0,0 +1,241 @@
+
+use errors::*;
+use std::time::{Duration, Instant};
+use ring::agreement::EphemeralPrivateKey;
+use cipher::{SessionKey, derive_key};
+use packets::{RekeyPacket, RekeyAckPacket};
+
+/// Limits on how long a single session key may be used before it must be
+/// replaced, and how long a replaced key is still accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    /// Rekey after this many packets have been sealed under one key.
+    /// The AEADs of every `CipherSuite` use random 96-bit nonces, and should
+    /// stay well under 2^32.
+    pub max_packets: u64,
+
+    /// Rekey after a key has been in use for this long
+    pub max_age: Duration,
+
+    /// How long the previous key is still accepted for incoming packets after
+    /// both sides have switched, so that packets already in flight are not
+    /// lost.
+    pub overlap: Duration,
+
+    /// How long to wait for a `RekeyAckPacket` before sending the
+    /// `RekeyPacket` again (see `Remote::rekey_retransmit()`).
+    pub retransmit_interval: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> RekeyPolicy {
+        RekeyPolicy {
+            max_packets: 1 << 24,
+            max_age: Duration::from_secs(60 * 60),
+            overlap: Duration::from_secs(10),
+            retransmit_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Rekeying state of a `Remote`.
+///
+/// A rekey is a three step exchange, each packet sealed under the key in use
+/// at the time:
+///   1. The initiator sends a `RekeyPacket` with a fresh X25519 public key.
+///   2. The responder replies with a `RekeyAckPacket` carrying its own fresh
+///      public key.  It keeps sending under the old key, but will now also
+///      accept the new one.
+///   3. The initiator switches to the new key.  The responder switches as
+///      soon as it receives the first packet sealed under it.
+///
+/// The initiator sends its `RekeyPacket` again until it is acknowledged, and
+/// the responder answers a repeated one with the same `RekeyAckPacket`.  The
+/// initiator keeps accepting the old key until a packet under the new key
+/// shows that the responder has switched too, so the link survives the
+/// initiator staying quiet after the switch.
+///
+/// The new key is derived from the old key and the new shared secret, so a
+/// compromised ephemeral key alone does not reveal it.
+pub struct RekeyState {
+    /// Limits which determine when a rekey is due
+    pub policy: RekeyPolicy,
+
+    /// How many times the session key has been replaced.  The key established
+    /// by the handshake is epoch 0.
+    pub epoch: u32,
+
+    /// Number of packets sealed under the current key
+    pub packets_sealed: u64,
+
+    /// When the current key was installed
+    pub key_installed: Instant,
+
+    /// The epoch and ephemeral private key of a rekey we initiated, which
+    /// has not been acknowledged yet.
+    pub pending: Option<(u32, EphemeralPrivateKey)>,
+
+    /// The `RekeyPacket` of the pending rekey, and when it was last sent
+    pub pending_packet: Option<(RekeyPacket, Instant)>,
+
+    /// The `RekeyPacket` we acknowledged and our answer to it, repeated if the
+    /// remote sends it again.
+    pub answered: Option<(RekeyPacket, RekeyAckPacket)>,
+
+    /// The key the remote is about to switch to, after we acknowledged its
+    /// rekey.
+    pub next_key: Option<SessionKey>,
+
+    /// The key we just replaced, and when it stops being accepted.
+    pub previous_key: Option<(SessionKey, Instant)>,
+
+    /// Whether the remote has been seen using the current key.  Until it has,
+    /// `previous_key` does not expire.
+    pub remote_switched: bool,
+}
+
+impl RekeyState {
+    pub fn new(policy: RekeyPolicy) -> RekeyState
+    {
+        RekeyState {
+            policy: policy,
+            epoch: 0,
+            packets_sealed: 0,
+            key_installed: Instant::now(),
+            pending: None,
+            pending_packet: None,
+            answered: None,
+            next_key: None,
+            previous_key: None,
+            remote_switched: true,
+        }
+    }
+
+    /// Whether the current key has exceeded the limits of the policy
+    pub fn limits_exceeded(&self) -> bool
+    {
+        self.packets_sealed >= self.policy.max_packets
+            || self.key_installed.elapsed() >= self.policy.max_age
+    }
+
+    /// Whether any key other than the current one could open an incoming packet
+    pub fn has_alternate_keys(&self) -> bool
+    {
+        self.next_key.is_some() || self.previous_key.is_some()
+    }
+
+    // Record that `old_key` was replaced, and reset the usage counters.
+    // `remote_switched` says whether the remote already uses the new key.
+    pub(crate) fn key_replaced(&mut self, old_key: SessionKey, remote_switched: bool)
+    {
+        let now = Instant::now();
+        self.epoch += 1;
+        self.packets_sealed = 0;
+        self.key_installed = now;
+        self.next_key = None;
+        self.answered = None;
+        self.previous_key = Some((old_key, now + self.policy.overlap));
+        self.remote_switched = remote_switched;
+    }
+
+    // Record that a packet from the remote opened under the current key.  The
+    // overlap for the previous key starts now, if it had not already.
+    pub(crate) fn current_key_used(&mut self)
+    {
+        if !self.remote_switched {
+            self.remote_switched = true;
+            if let Some((_, ref mut expires)) = self.previous_key {
+                *expires = Instant::now() + self.policy.overlap;
+            }
+        }
+    }
+
+    // Whether the previous key may still open incoming packets
+    pub(crate) fn previous_key_valid(&self) -> bool
+    {
+        match self.previous_key {
+            Some((_, expires)) => !self.remote_switched || Instant::now() < expires,
+            None => false,
+        }
+    }
+}
+
+// Derive the session key for `epoch` (with the same cipher suite) from the
+// current one and a fresh shared secret
+pub(crate) fn derive_next_key(current_key: &SessionKey, epoch: u32, shared_secret: &[u8])
+                              -> Result<SessionKey>
+{
+    derive_key(current_key.cipher_suite(), epoch, &current_key.bytes()[..], shared_secret,
+               b"siege-net rekey")
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use ring::rand::SystemRandom;
+    use remote::Remote;
+    use cipher::CipherSuite;
+    use packets::{InitPacket, InitAckPacket, HeartbeatPacket};
+
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("127.0.0.1:5555").unwrap();
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng.clone()).unwrap();
+
+    // Handshake
+    let init = InitPacket::new(&mut client).unwrap();
+    let init_ack = InitAckPacket::new(&server, CipherSuite::Aes128Gcm, &[0; 64]).unwrap();
+    let suite = CipherSuite::ChaCha20Poly1305;
+    server.compute_session_key(&init.public_key, suite).unwrap();
+    client.compute_session_key(&init_ack.public_key, suite).unwrap();
+    assert_eq!(client.session_key, server.session_key);
+    let handshake_key = *client.session_key.bytes();
+
+    client.rekey.policy.max_packets = 1;
+    assert!(!client.rekey_due());
+    let mut bytes = client.serialize_packet(&HeartbeatPacket::new(), 0xABCDE000, 1).unwrap();
+    server.deserialize_packet_header::<HeartbeatPacket>(&mut bytes[..]).unwrap();
+    assert!(client.rekey_due());
+
+    // Rekey exchange.  The first RekeyPacket is lost, so it is sent again.
+    client.rekey.policy.retransmit_interval = Duration::from_secs(0);
+    let rekey = client.begin_rekey().unwrap();
+    assert!(!client.rekey_due());
+    assert_eq!(client.rekey_retransmit(), Some(rekey.clone()));
+    let rekey_ack = server.accept_rekey(&rekey).unwrap().unwrap();
+    assert_eq!(server.session_key.bytes(), &handshake_key);
+
+    // The ack is lost too; the repeated RekeyPacket gets the same answer
+    assert_eq!(server.accept_rekey(&rekey).unwrap().unwrap(), rekey_ack);
+    client.complete_rekey(&rekey_ack).unwrap();
+    assert_eq!(client.rekey_retransmit(), None);
+    assert_ne!(client.session_key.bytes(), &handshake_key);
+    assert_eq!(client.session_key.cipher_suite(), suite);
+    assert_eq!(client.rekey.epoch, 1);
+
+    // Until the server is seen using the new key, the old key is accepted
+    // even after the overlap
+    client.rekey.policy.overlap = Duration::from_secs(0);
+    let mut bytes = server.serialize_packet(&HeartbeatPacket::new(), 0xABCDE000, 1).unwrap();
+    client.deserialize_packet_header::<HeartbeatPacket>(&mut bytes[..]).unwrap();
+
+    // The first packet under the new key moves the server over
+    let mut bytes = client.serialize_packet(&HeartbeatPacket::new(), 0xABCDE000, 1).unwrap();
+    server.deserialize_packet_header::<HeartbeatPacket>(&mut bytes[..]).unwrap();
+    assert_eq!(server.session_key, client.session_key);
+    assert_eq!(server.rekey.epoch, 1);
+
+    // Once the server has used the new key and the overlap has ended, the old
+    // key is refused
+    let mut stale = Remote::new(addr, rng).unwrap();
+    stale.session_key = SessionKey::new(suite, &handshake_key).unwrap();
+    let mut bytes = stale.serialize_packet(&HeartbeatPacket::new(), 0xABCDE000, 1).unwrap();
+    let mut copy = bytes.clone();
+    client.deserialize_packet_header::<HeartbeatPacket>(&mut copy[..]).unwrap();
+    let mut reply = server.serialize_packet(&HeartbeatPacket::new(), 0xABCDE000, 1).unwrap();
+    client.deserialize_packet_header::<HeartbeatPacket>(&mut reply[..]).unwrap();
+    assert!(client.rekey.remote_switched);
+    assert!(client.deserialize_packet_header::<HeartbeatPacket>(&mut bytes[..]).is_err());
+}
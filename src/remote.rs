@@ -1,6 +1,9 @@
 
 use errors::*;
 use std::sync::Arc;
+use std::time::Instant;
+use std::mem::replace;
+use std::marker::PhantomData;
 use std::net::SocketAddr;
 use serde::{Serialize, Deserialize};
 use ring::rand::{SystemRandom, SecureRandom};
@@ -9,16 +12,60 @@ use ring::signature::ED25519;
 use ring::error::Unspecified;
 use untrusted::Input;
 use timestamp::Timestamp;
-use packets::{Packet, Header};
+use cookie::Cookie;
+use token::ConnectToken;
+use cipher::{CipherSuite, SessionKey, negotiate, derive_key};
+use pool::{BufferPool, PooledBuffer};
+use scheduler::{Scheduler, IfUnsent};
+use sender::{BatchSender, DatagramSink};
+use codec::{Codec, BincodeCodec};
+use packets::{Packet, Header, Flags, InitAckPacket, RekeyPacket, RekeyAckPacket,
+              PathChallengePacket, PathResponsePacket, handshake_transcript,
+              AMPLIFICATION_FACTOR, MAX_PROTO_PACKET, NONCE_OFFSET, PREFIX_SIZE};
+use rekey::{RekeyState, RekeyPolicy, derive_next_key};
 
-/// Information about the remote entity you are communicating with
-pub struct Remote {
+/// What the header of a received packet said
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketMeta {
+    pub sequence_number: u32,
+
+    /// The sequence number of the packet this one answers, if it is a reply
+    pub in_reply_to: Option<u32>,
+
+    pub flags: Flags,
+
+    /// When the remote sent the packet, by the remote's clock
+    pub timestamp: Timestamp,
+
+    /// Whether the packet is out of order or a duplicate
+    pub stale: bool,
+}
+
+/// Information about the remote entity you are communicating with.  Packet
+/// bodies are encoded with the codec `C` (see `Codec`).
+pub struct Remote<C = BincodeCodec> {
     /// Random number generator
     pub rng: Arc<SystemRandom>,
 
     /// The remote's IP address and port
     pub addr: SocketAddr,
 
+    /// Identifies this session in every datagram, so that it can be found even
+    /// if the remote's address changes.  Assigned by the server, and zero on the
+    /// client until the server's `InitAckPacket` arrives.
+    pub connection_id: u64,
+
+    /// A new address the remote appears to have moved to, along with the data
+    /// of the `PathChallengePacket` we sent there.  `addr` only changes once the
+    /// matching `PathResponsePacket` arrives from that address.
+    pub pending_path: Option<(SocketAddr, [u8; 8])>,
+
+    /// Bytes received from and sent to the `pending_path` address.  Like an
+    /// unvalidated remote, it is sent no more than `AMPLIFICATION_FACTOR` times
+    /// the bytes received from it.
+    pub path_bytes_received: usize,
+    pub path_bytes_sent: usize,
+
     /// The next sequence number we will use when sending packets to the remote
     pub next_local_seq_number: u32,
 
@@ -29,9 +76,20 @@ pub struct Remote {
     /// for the first packet exchange, and then set to None.
     pub eph_private_key: Option<EphemeralPrivateKey>,
 
-    /// The shared session key used for AEAD of packet contents.  Starts as zeroes
-    /// until a better session key has been established via key exchange.
-    pub session_key: [u8; 16],
+    /// The shared session key used for AEAD of packet contents, along with the
+    /// cipher suite it is for.  Starts as the all-zero `Aes128Gcm` key until a
+    /// better session key has been established via key exchange.
+    pub session_key: SessionKey,
+
+    /// The cipher suites we support, most preferred first.  A client offers
+    /// these in its `InitPacket`; a server accepts the first one offered that
+    /// is also in this list.
+    pub cipher_suites: Vec<CipherSuite>,
+
+    /// A session key computed by the server while answering the handshake.
+    /// Until the first packet sealed under it arrives, packets are still sealed
+    /// under `session_key`, so that the client can read the `InitAckPacket`.
+    pub pending_session_key: Option<SessionKey>,
 
     /// A nonce used to help verify the remote is authentic.  Only used by the Client
     /// and only used during the first packet exchange.
@@ -50,29 +108,119 @@ pub struct Remote {
 
     /// The maximum possible offset of the remote's clock as compared to our clock.
     pub offset_max: Option<i32>,
+
+    /// Session rekeying state, including keys still accepted during a switch.
+    pub rekey: RekeyState,
+
+    /// A cookie the server asked us to echo back in our `InitPacket`.  Only
+    /// used by the Client.
+    pub cookie: Option<Cookie>,
+
+    /// Whether the remote has proven it can receive packets at `addr`.  Until
+    /// it has, we send no more than `AMPLIFICATION_FACTOR` times the bytes we
+    /// received from it.  This becomes true when we contact the remote first,
+    /// or when a packet from it opens under an established session key.  A
+    /// server which checked a `Cookie` from the remote may also set it.
+    pub address_validated: bool,
+
+    /// Bytes received from the remote (counted before decryption)
+    pub bytes_received: usize,
+
+    /// Bytes sent to the remote
+    pub bytes_sent: usize,
+
+    /// The transcript of the completed handshake (see
+    /// `packets::handshake_transcript()`), which a client with an identity of
+    /// its own signs to authenticate itself.
+    pub handshake_transcript: Option<Vec<u8>>,
+
+    /// The identity key the remote authenticated itself with, if the server
+    /// requires one.  Only used by the Server.
+    pub client_public_key: Option<[u8; 32]>,
+
+    /// Whether the server has accepted this session (see `ServerIdentity`).
+    /// Only used by the Server.
+    pub established: bool,
+
+    /// The connect token the remote authorized itself with, if the server
+    /// requires one.  Only used by the Server.
+    pub connect_token: Option<ConnectToken>,
+
+    /// Outbound messages waiting to be sent by priority, within a budget of
+    /// bytes per tick (see `schedule()`).  The budget is unlimited until set.
+    pub scheduler: Scheduler,
+
+    codec: PhantomData<C>,
 }
 
 impl Remote {
     pub fn new(addr: SocketAddr, rng: Arc<SystemRandom>) -> Result<Remote>
+    {
+        Remote::with_codec(addr, rng)
+    }
+
+    /// A remote without an ephemeral private key.  This is cheap, and enough
+    /// to read an `InitPacket` and answer it with a `RetryPacket`.  Call
+    /// `generate_ephemeral_key()` before going on with the handshake.
+    pub fn new_unkeyed(addr: SocketAddr, rng: Arc<SystemRandom>) -> Remote
+    {
+        Remote::with_codec_unkeyed(addr, rng)
+    }
+}
+
+impl<C: Codec> Remote<C> {
+    /// Like `Remote::new()`, but encoding packet bodies with the codec `C`
+    pub fn with_codec(addr: SocketAddr, rng: Arc<SystemRandom>) -> Result<Remote<C>>
+    {
+        let mut remote = Remote::with_codec_unkeyed(addr, rng);
+        remote.generate_ephemeral_key()?;
+        Ok(remote)
+    }
+
+    /// Like `Remote::new_unkeyed()`, but encoding packet bodies with the
+    /// codec `C`
+    pub fn with_codec_unkeyed(addr: SocketAddr, rng: Arc<SystemRandom>) -> Remote<C>
     {
         let mut nonce: [u8; 12] = [0; 12]; // 96 bit nonce
         rng.fill(&mut nonce[..12]).unwrap();
 
-        let eph_private_key = EphemeralPrivateKey::generate(&X25519, &*rng)?;
-
-        Ok(Remote {
+        Remote {
             rng: rng,
             addr: addr,
+            connection_id: 0,
+            pending_path: None,
+            path_bytes_received: 0,
+            path_bytes_sent: 0,
             next_local_seq_number: 1,
             last_remote_seq_number: 0,
-            eph_private_key: Some(eph_private_key),
-            session_key: [0; 16],
+            eph_private_key: None,
+            session_key: SessionKey::handshake(),
+            cipher_suites: CipherSuite::all(),
+            pending_session_key: None,
             nonce: nonce,
             sent_pings: [(0, Timestamp::now()); 3],
             sent_ping_write_index: 0,
             offset_min: None,
             offset_max: None,
-        })
+            rekey: RekeyState::new(RekeyPolicy::default()),
+            cookie: None,
+            address_validated: false,
+            bytes_received: 0,
+            bytes_sent: 0,
+            handshake_transcript: None,
+            client_public_key: None,
+            established: false,
+            connect_token: None,
+            scheduler: Scheduler::default(),
+            codec: PhantomData,
+        }
+    }
+
+    /// Generate the ephemeral private key used to establish the session key
+    pub fn generate_ephemeral_key(&mut self) -> Result<()>
+    {
+        self.eph_private_key = Some(EphemeralPrivateKey::generate(&X25519, &*self.rng)?);
+        Ok(())
     }
 
     pub fn serialize_packet<P: Packet + Serialize>(
@@ -82,7 +230,7 @@ impl Remote {
         version: u32)
         -> Result<Vec<u8>>
     {
-        self._serialize_packet(packet, magic, version, None)
+        self._serialize_packet::<C, P>(packet, magic, version, None)
     }
 
     pub fn serialize_reply_packet<P: Packet + Serialize>(
@@ -93,10 +241,241 @@ impl Remote {
         in_reply_to: u32)
         -> Result<Vec<u8>>
     {
-        self._serialize_packet(packet, magic, version, Some(in_reply_to))
+        self._serialize_packet::<C, P>(packet, magic, version, Some(in_reply_to))
+    }
+
+    /// Like `serialize_packet()`, but writes the datagram into `buf` (which
+    /// must hold at least `serialized_packet_size()` bytes) instead of a new
+    /// `Vec`.  Returns the number of bytes written.
+    pub fn serialize_packet_into<P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        buf: &mut [u8])
+        -> Result<usize>
+    {
+        self._serialize_packet_into::<C, P>(packet, magic, version, None, buf)
+    }
+
+    /// Like `serialize_reply_packet()`, but writes the datagram into `buf`.
+    /// Returns the number of bytes written.
+    pub fn serialize_reply_packet_into<P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        in_reply_to: u32,
+        buf: &mut [u8])
+        -> Result<usize>
+    {
+        self._serialize_packet_into::<C, P>(packet, magic, version, Some(in_reply_to), buf)
+    }
+
+    /// Like `serialize_packet()`, but writes the datagram into a buffer taken
+    /// from `pool`.  Fails with `PoolExhausted` if none is free.
+    pub fn serialize_packet_pooled<P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        pool: &BufferPool)
+        -> Result<PooledBuffer>
+    {
+        self._serialize_packet_pooled::<C, P>(packet, magic, version, None, pool)
+    }
+
+    /// Like `serialize_reply_packet()`, but writes the datagram into a buffer
+    /// taken from `pool`.
+    pub fn serialize_reply_packet_pooled<P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        in_reply_to: u32,
+        pool: &BufferPool)
+        -> Result<PooledBuffer>
+    {
+        self._serialize_packet_pooled::<C, P>(packet, magic, version, Some(in_reply_to), pool)
+    }
+
+    /// Like `serialize_packet()`, but encoding the body with the codec `D`
+    /// instead of this remote's own.  The receiver must use
+    /// `deserialize_packet_with()` with the same codec.
+    pub fn serialize_packet_with<D: Codec, P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32)
+        -> Result<Vec<u8>>
+    {
+        self._serialize_packet::<D, P>(packet, magic, version, None)
+    }
+
+    /// Like `serialize_reply_packet()`, but encoding the body with the codec `D`
+    pub fn serialize_reply_packet_with<D: Codec, P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        in_reply_to: u32)
+        -> Result<Vec<u8>>
+    {
+        self._serialize_packet::<D, P>(packet, magic, version, Some(in_reply_to))
     }
 
-    fn _serialize_packet<P: Packet + Serialize>(
+    /// Like `serialize_packet_into()`, but encoding the body with the codec `D`.
+    /// `buf` must hold at least `serialized_packet_size_with::<D, _>()` bytes.
+    pub fn serialize_packet_into_with<D: Codec, P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        buf: &mut [u8])
+        -> Result<usize>
+    {
+        self._serialize_packet_into::<D, P>(packet, magic, version, None, buf)
+    }
+
+    /// Like `serialize_reply_packet_into()`, but encoding the body with the
+    /// codec `D`
+    pub fn serialize_reply_packet_into_with<D: Codec, P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        in_reply_to: u32,
+        buf: &mut [u8])
+        -> Result<usize>
+    {
+        self._serialize_packet_into::<D, P>(packet, magic, version, Some(in_reply_to), buf)
+    }
+
+    /// Like `serialize_packet_pooled()`, but encoding the body with the codec `D`
+    pub fn serialize_packet_pooled_with<D: Codec, P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        pool: &BufferPool)
+        -> Result<PooledBuffer>
+    {
+        self._serialize_packet_pooled::<D, P>(packet, magic, version, None, pool)
+    }
+
+    /// Like `serialize_reply_packet_pooled()`, but encoding the body with the
+    /// codec `D`
+    pub fn serialize_reply_packet_pooled_with<D: Codec, P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        in_reply_to: u32,
+        pool: &BufferPool)
+        -> Result<PooledBuffer>
+    {
+        self._serialize_packet_pooled::<D, P>(packet, magic, version, Some(in_reply_to), pool)
+    }
+
+    /// Queue `packet` under `key` (such as the entity it describes), to be
+    /// sent by `send_scheduled()` once its accumulated priority earns it a
+    /// place in the tick's budget.  A packet still waiting under `key` is
+    /// replaced.  Fails if the packet could never fit in the budget, or the
+    /// priority is not finite or is negative.
+    pub fn schedule<P: Packet + Serialize>(
+        &mut self,
+        key: u64,
+        packet: &P,
+        priority: f32,
+        if_unsent: IfUnsent)
+        -> Result<()>
+    {
+        let mut body = vec![0; C::serialized_size(packet)?];
+        let len = C::serialize_into(packet, &mut body[..])?;
+        body.truncate(len);
+        if self.datagram_size(len)? > self.scheduler.budget() {
+            return Err("Packet is larger than the scheduler's budget.".into());
+        }
+        self.scheduler.insert(key, body, packet.reply_expected(), priority, if_unsent)
+    }
+
+    /// Seal the most important scheduled packets which fit in this tick's
+    /// budget into buffers from `pool`, and queue them on `sender`.  Call once
+    /// per tick.  Returns the number of datagrams queued.  On failure, the
+    /// packets not yet queued stay scheduled.
+    pub fn send_scheduled<S: DatagramSink>(
+        &mut self,
+        magic: u32,
+        version: u32,
+        pool: &BufferPool,
+        sender: &mut BatchSender<S>)
+        -> Result<usize>
+    {
+        let overhead = self.datagram_size(0)?;
+        let mut chosen = self.scheduler.tick(overhead).into_iter();
+        let mut count = 0;
+        while let Some((key, entry)) = chosen.next() {
+            let error = match self.seal_encoded_pooled(&entry.body, entry.reply_expected, magic,
+                                                       version, pool) {
+                Ok(datagram) => match sender.push(self.addr, datagram) {
+                    Ok(()) => {
+                        count += 1;
+                        continue;
+                    },
+                    Err(e @ Error(ErrorKind::SendQueueFull, _)) => e,
+                    Err(e) => {
+                        // The datagram was queued, so it must not be sent again
+                        warn!("Sending scheduled packets to {} failed: {}", self.addr, e);
+                        count += 1;
+                        continue;
+                    },
+                },
+                Err(e) => e,
+            };
+
+            // Keep what was not queued for the next tick
+            self.scheduler.restore(key, entry);
+            for (key, entry) in chosen {
+                self.scheduler.restore(key, entry);
+            }
+            return Err(error);
+        }
+        Ok(count)
+    }
+
+    /// The size of the datagram `packet` serializes to, under the current
+    /// cipher suite
+    pub fn serialized_packet_size<P: Packet + Serialize>(&self, packet: &P) -> Result<usize>
+    {
+        self._serialized_packet_size::<C, P>(packet)
+    }
+
+    /// The size of the datagram `packet` serializes to with the codec `D`
+    pub fn serialized_packet_size_with<D: Codec, P: Packet + Serialize>(&self, packet: &P)
+                                                                      -> Result<usize>
+    {
+        self._serialized_packet_size::<D, P>(packet)
+    }
+
+    fn _serialized_packet_size<D: Codec, P: Packet + Serialize>(&self, packet: &P)
+                                                                -> Result<usize>
+    {
+        self.datagram_size(D::serialized_size(packet)?)
+    }
+
+    // The size of a datagram carrying a body of `body_size` bytes
+    pub(crate) fn datagram_size(&self, body_size: usize) -> Result<usize>
+    {
+        use bincode::serialized_size;
+
+        let header = Header::new(Timestamp::from_raw(0), 0, None, 1500);
+        Ok(PREFIX_SIZE +
+           serialized_size(&header)? as usize +
+           body_size +
+           self.session_key.cipher_suite().tag_len())
+    }
+
+    fn _serialize_packet<D: Codec, P: Packet + Serialize>(
         &mut self,
         packet: &P,
         magic: u32,
@@ -104,15 +483,107 @@ impl Remote {
         in_reply_to: Option<u32>)
         -> Result<Vec<u8>>
     {
-        use std::io::Cursor;
-        use ring::aead::{AES_128_GCM, SealingKey, seal_in_place};
-        use bincode::{serialized_size, serialize_into};
+        let mut bytes: Vec<u8> = vec![0; self._serialized_packet_size::<D, P>(packet)?];
+        let len = self._serialize_packet_into::<D, P>(packet, magic, version, in_reply_to,
+                                                      &mut bytes[..])?;
+        bytes.truncate(len);
+        Ok(bytes)
+    }
+
+    fn _serialize_packet_pooled<D: Codec, P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        in_reply_to: Option<u32>,
+        pool: &BufferPool)
+        -> Result<PooledBuffer>
+    {
+        let mut buf = match pool.acquire() {
+            Some(buf) => buf,
+            None => return Err(ErrorKind::PoolExhausted.into()),
+        };
+        let len = self._serialize_packet_into::<D, P>(packet, magic, version, in_reply_to,
+                                                      &mut buf[..])?;
+        buf.set_len(len);
+        Ok(buf)
+    }
+
+    fn _serialize_packet_into<D: Codec, P: Packet + Serialize>(
+        &mut self,
+        packet: &P,
+        magic: u32,
+        version: u32,
+        in_reply_to: Option<u32>,
+        buf: &mut [u8])
+        -> Result<usize>
+    {
+        self.seal_into(D::serialized_size(packet)?, packet.reply_expected(), magic, version,
+                       in_reply_to, buf, |body| D::serialize_into(packet, body).map(|_| ()))
+    }
+
+    /// Seal a body encoded beforehand (see `Broadcast`) into `buf`, as
+    /// `serialize_packet_into()` would.  Returns the number of bytes written.
+    pub(crate) fn seal_encoded_into(
+        &mut self,
+        body: &[u8],
+        reply_expected: bool,
+        magic: u32,
+        version: u32,
+        buf: &mut [u8])
+        -> Result<usize>
+    {
+        self.seal_into(body.len(), reply_expected, magic, version, None, buf,
+                       |dest| { dest[..body.len()].copy_from_slice(body); Ok(()) })
+    }
+
+    /// Seal a body encoded beforehand into a buffer taken from `pool`
+    pub(crate) fn seal_encoded_pooled(
+        &mut self,
+        body: &[u8],
+        reply_expected: bool,
+        magic: u32,
+        version: u32,
+        pool: &BufferPool)
+        -> Result<PooledBuffer>
+    {
+        let mut buf = match pool.acquire() {
+            Some(buf) => buf,
+            None => return Err(ErrorKind::PoolExhausted.into()),
+        };
+        let len = self.seal_encoded_into(body, reply_expected, magic, version, &mut buf[..])?;
+        buf.set_len(len);
+        Ok(buf)
+    }
+
+    // Write the prefix and header into `buf`, then the body (of `body_size`
+    // bytes, by `write_body`), and seal it all
+    #[allow(clippy::too_many_arguments)]
+    fn seal_into<F>(
+        &mut self,
+        body_size: usize,
+        reply_expected: bool,
+        magic: u32,
+        version: u32,
+        in_reply_to: Option<u32>,
+        buf: &mut [u8],
+        write_body: F)
+        -> Result<usize>
+        where F: FnOnce(&mut [u8]) -> Result<()>
+    {
+        use bincode::serialize_into;
+
+        let fullsize = self.datagram_size(body_size)?;
+        if buf.len() < fullsize {
+            return Err(ErrorKind::BufferTooSmall(fullsize).into());
+        }
+        self.check_amplification_limit(fullsize)?;
 
         // Build the header
         let seq = self.next_seq_number();
         let now = Timestamp::now();
 
-        if packet.reply_expected() {
+        if reply_expected {
             // Save timestamp for packets we expect to get a reply to
             self.sent_pings[self.sent_ping_write_index] = (seq, now);
             self.sent_ping_write_index = (self.sent_ping_write_index + 1) % 3;
@@ -120,59 +591,69 @@ impl Remote {
 
         let header = Header::new(now, seq, in_reply_to, 1500);
 
-        // Prepare serialization area
-        const MAGIC_AND_VERSION_SIZE: usize = 4;
-        const NONCE_SIZE: usize = 12;
-        const SUFFIX_SIZE: usize = 16; //  AES_128_GCM.max_overhead_len() is 16;
-        let fullsize =
-            MAGIC_AND_VERSION_SIZE +
-            NONCE_SIZE +
-            serialized_size(&header)? as usize +
-            serialized_size(packet)? as usize +
-            SUFFIX_SIZE;
-        let bytes: Vec<u8> = Vec::with_capacity(fullsize);
-
-        // Serialize in the magic and version
-        let mut bytes: Vec<u8> = {
-            let mut cursor = Cursor::new(bytes);
+        // Serialize in the magic, version and connection id
+        {
+            let mut prefix = &mut buf[..NONCE_OFFSET];
             let magic_and_version: u32 = magic | version;
-            serialize_into(&mut cursor, &magic_and_version)?;
-            cursor.into_inner()
-        };
+            serialize_into(&mut prefix, &magic_and_version)?;
+            serialize_into(&mut prefix, &self.connection_id)?;
+        }
 
         // Write in a random nonce
-        bytes.extend([0; 12].into_iter());
-        self.rng.fill(&mut bytes[4..4+12]).unwrap();
-
-        // Serialize in the header
-        let bytes: Vec<u8> = {
-            let len = bytes.len();
-            let mut cursor = Cursor::new(bytes);
-            cursor.set_position(len as u64);
-            serialize_into(&mut cursor, &header)?;
-            cursor.into_inner()
-        };
+        self.rng.fill(&mut buf[NONCE_OFFSET..PREFIX_SIZE])?;
 
-        // Serialize in the packet
-        let mut bytes: Vec<u8> = {
-            let len = bytes.len();
-            let mut cursor = Cursor::new(bytes);
-            cursor.set_position(len as u64);
-            serialize_into(&mut cursor, packet)?;
-            cursor.into_inner()
-        };
+        // Serialize in the header and the packet
+        {
+            let mut body = &mut buf[PREFIX_SIZE..fullsize];
+            serialize_into(&mut body, &header)?;
+            write_body(body)?;
+        }
 
-        // Encrypt/Sign
-        bytes.extend([0; SUFFIX_SIZE].into_iter());
-        // mav = magic and version.  This is the "associated data"
-        let mavbytes = &bytes[0..4].to_vec(); // copy to appease borrow checker ;-(
-        let nonce = &bytes[4..4+12].to_vec(); // copy to appease borrow checker :-(
-        let sealing_key = try!(SealingKey::new(&AES_128_GCM, &self.session_key));
-        let size = try!(seal_in_place(&sealing_key, &*nonce, &*mavbytes,
-                                      &mut bytes[16..], SUFFIX_SIZE));
-        bytes.truncate(16+size);
+        // Encrypt/Sign, into the room left at the end for the tag
+        let size = self.session_key.seal(&mut buf[..fullsize])?;
+        self.rekey.packets_sealed += 1;
+        self.bytes_sent += PREFIX_SIZE + size;
 
-        Ok(bytes)
+        Ok(PREFIX_SIZE + size)
+    }
+
+    /// Open a datagram and deserialize the packet in it.  Returns the packet
+    /// along with what its header said (see `PacketMeta`).
+    pub fn deserialize_packet<'a, P: Packet + Deserialize<'a>>(
+        &mut self,
+        bytes: &'a mut [u8])
+        -> Result<(P, PacketMeta)>
+    {
+        self.deserialize_packet_with::<C, P>(bytes)
+    }
+
+    /// Like `deserialize_packet()`, but decoding the body with the codec `D`
+    /// instead of this remote's own
+    pub fn deserialize_packet_with<'a, D: Codec, P: Packet + Deserialize<'a>>(
+        &mut self,
+        bytes: &'a mut [u8])
+        -> Result<(P, PacketMeta)>
+    {
+        let (body, meta) = self.open_datagram(bytes)?;
+        let packet: P = D::deserialize(body)?;
+        Ok((packet, meta))
+    }
+
+    /// Open a datagram like `deserialize_packet()`, but return the serialized
+    /// packet instead of deserializing it (see `Router`).
+    pub fn open_datagram<'a>(&mut self, bytes: &'a mut [u8]) -> Result<(&'a [u8], PacketMeta)>
+    {
+        let (body, header, stale) = self.open_packet(bytes)?;
+        Ok((body, PacketMeta {
+            sequence_number: header.sequence_number,
+            in_reply_to: match header.in_reply_to {
+                0 => None,
+                seq => Some(seq),
+            },
+            flags: header.flags,
+            timestamp: Timestamp::from_raw(header.timestamp),
+            stale: stale,
+        }))
     }
 
     // Returns the packet bytes along with the sequence number from the header
@@ -183,17 +664,52 @@ impl Remote {
         bytes: &'a mut [u8])
         -> Result<(&'a [u8], u32, bool)>
     {
-        use ring::aead::{AES_128_GCM, OpeningKey, open_in_place};
+        let (packet, header, stale) = self.open_packet(bytes)?;
+        Ok((packet, header.sequence_number, stale))
+    }
+
+    // Open a datagram and process its header.  Returns the packet bytes, the
+    // header and whether the packet is stale.
+    fn open_packet<'a>(&mut self, bytes: &'a mut [u8]) -> Result<(&'a [u8], Header, bool)>
+    {
         use bincode::{deserialize, serialized_size};
 
-        // Decrypt
-        let mavbytes = &bytes[0..4].to_vec(); // copy to appease borrow checker ;-(
-        let nonce = &bytes[4..4+12].to_vec(); // copy to appease borrow checker ;-(
-        let opening_key = try!(OpeningKey::new(&AES_128_GCM, &self.session_key));
-        let slice = open_in_place(&opening_key, &*nonce, &*mavbytes, 0, &mut bytes[16..])?;
+        self.bytes_received += bytes.len();
+        if bytes.len() < PREFIX_SIZE || bytes.len() - PREFIX_SIZE > MAX_PROTO_PACKET {
+            return Err(ErrorKind::InvalidPacket.into());
+        }
+
+        // Decrypt.  A failed attempt zeroes the buffer, so keep a copy if there
+        // are other keys (from the handshake or a rekey) to fall back on.
+        let mut storage: [u8; MAX_PROTO_PACKET];
+        let backup = if self.rekey.has_alternate_keys() || self.pending_session_key.is_some() {
+            let backup_len = bytes.len() - PREFIX_SIZE;
+            storage = [0; MAX_PROTO_PACKET];
+            storage[..backup_len].copy_from_slice(&bytes[PREFIX_SIZE..]);
+            Some(&storage[..backup_len])
+        } else {
+            None
+        };
+        let len = match self.session_key.open(bytes) {
+            Ok(len) => {
+                self.rekey.current_key_used();
+                len
+            },
+            Err(e) => match backup {
+                Some(backup) => self.open_with_alternate_key(bytes, backup)?,
+                None => return Err(e),
+            },
+        };
+        let slice = &bytes[PREFIX_SIZE..PREFIX_SIZE+len];
+
+        // Only the remote at `addr` could have received our session key
+        if !self.address_validated && !self.session_key.is_handshake_key() {
+            self.address_validated = true;
+        }
 
         // Deserialize the header
         let header: Header = deserialize(slice)?;
+        if !header.is_valid() { return Err(ErrorKind::InvalidPacket.into()); }
 
         // Deserialize the packet body
         let offset = serialized_size(&header)? as usize;
@@ -228,7 +744,62 @@ impl Remote {
         }
 
         // Return the packet
-        Ok((packet, header.sequence_number, stale))
+        Ok((packet, header, stale))
+    }
+
+    // Refuse to send `len` more bytes to an unvalidated address if that would
+    // make us an amplifier
+    fn check_amplification_limit(&mut self, len: usize) -> Result<()>
+    {
+        if self.address_validated {
+            return Ok(());
+        }
+        if self.bytes_received == 0 && self.bytes_sent == 0 {
+            // We are contacting the remote first, so we chose its address
+            self.address_validated = true;
+            return Ok(());
+        }
+        if self.bytes_sent + len > self.bytes_received * AMPLIFICATION_FACTOR {
+            return Err(ErrorKind::AmplificationLimit.into());
+        }
+        Ok(())
+    }
+
+    // Try the key the remote is switching to, then the key we are switching
+    // away from.  Returns the plaintext length.
+    fn open_with_alternate_key(&mut self, bytes: &mut [u8], backup: &[u8]) -> Result<usize>
+    {
+        if let Some(pending_key) = self.pending_session_key.take() {
+            bytes[PREFIX_SIZE..].copy_from_slice(backup);
+            if let Ok(len) = pending_key.open(bytes) {
+                // The client has the session key, so we can use it too
+                self.session_key = pending_key;
+                return Ok(len);
+            }
+            self.pending_session_key = Some(pending_key);
+        }
+
+        if let Some(next_key) = self.rekey.next_key.take() {
+            bytes[PREFIX_SIZE..].copy_from_slice(backup);
+            if let Ok(len) = next_key.open(bytes) {
+                // The remote has switched, so we switch too
+                let old_key = replace(&mut self.session_key, next_key);
+                self.rekey.key_replaced(old_key, true);
+                debug!("Rekeyed to epoch {} by remote", self.rekey.epoch);
+                return Ok(len);
+            }
+            self.rekey.next_key = Some(next_key);
+        }
+
+        if self.rekey.previous_key_valid() {
+            if let Some((ref previous_key, _)) = self.rekey.previous_key {
+                bytes[PREFIX_SIZE..].copy_from_slice(backup);
+                return previous_key.open(bytes);
+            }
+        }
+        self.rekey.previous_key = None;
+
+        Err(ErrorKind::Crypto(Unspecified).into())
     }
 
     pub fn next_seq_number(&mut self) -> u32
@@ -242,17 +813,172 @@ impl Remote {
         self.rng.fill(&mut self.nonce[..12]).unwrap();
     }
 
-    pub fn compute_session_key(&mut self, remote_public_key: &[u8; 32])
+    /// The cipher suite packets are currently sealed with
+    pub fn cipher_suite(&self) -> CipherSuite
+    {
+        self.session_key.cipher_suite()
+    }
+
+    /// Agree on a session key with the remote's ephemeral public key.  The key
+    /// is bound to `handshake_transcript`, so set that first.
+    pub fn compute_session_key(&mut self, remote_public_key: &[u8; 32],
+                               cipher_suite: CipherSuite)
                                -> Result<()>
+    {
+        self.session_key = self.agree_session_key(remote_public_key, cipher_suite)?;
+        self.rekey = RekeyState::new(self.rekey.policy);
+
+        Ok(())
+    }
+
+    /// Like `compute_session_key()`, but the key is only used once a packet
+    /// sealed under it arrives (see `pending_session_key`).  Used by the server,
+    /// which has to send its `InitAckPacket` under the old key.
+    pub fn compute_pending_session_key(&mut self, remote_public_key: &[u8; 32],
+                                       cipher_suite: CipherSuite)
+                                       -> Result<()>
+    {
+        let session_key = self.agree_session_key(remote_public_key, cipher_suite)?;
+        self.pending_session_key = Some(session_key);
+        self.rekey = RekeyState::new(self.rekey.policy);
+        Ok(())
+    }
+
+    // Agree on a session key using our ephemeral private key
+    fn agree_session_key(&mut self, remote_public_key: &[u8; 32], cipher_suite: CipherSuite)
+                         -> Result<SessionKey>
     {
         let eph = match self.eph_private_key.take() {
             Some(eph) => eph,
             None => return Err("Ephemeral private key was already used.".into()),
         };
-        self.session_key = agree_ephemeral(
+        let transcript = self.handshake_transcript.as_ref().map_or(&[][..], |t| &t[..]);
+        agree_ephemeral(
             eph, &X25519, Input::from(remote_public_key),
             ErrorKind::Crypto(Unspecified).into(),
-            key_derivation_function)?;
+            |secret| derive_key(cipher_suite, 0, transcript, secret, b"siege-net session key"))
+    }
+
+    /// Choose the cipher suite for a session, from the suites a client
+    /// offered.  Done by the server while answering the handshake.
+    pub fn negotiate_cipher_suite(&self, offered: &[CipherSuite]) -> Result<CipherSuite>
+    {
+        match negotiate(offered, &self.cipher_suites) {
+            Some(suite) => Ok(suite),
+            None => Err("No cipher suite in common.".into()),
+        }
+    }
+
+    /// Whether the session key has been used up according to the rekey policy,
+    /// and we should start a rekey with `begin_rekey()`.
+    pub fn rekey_due(&self) -> bool
+    {
+        self.eph_private_key.is_none()
+            && self.pending_session_key.is_none()
+            && self.rekey.pending.is_none()
+            && self.rekey.limits_exceeded()
+    }
+
+    /// Start a rekey.  The returned packet must be sent to the remote, which
+    /// answers with a `RekeyAckPacket` to pass to `complete_rekey()`.
+    pub fn begin_rekey(&mut self) -> Result<RekeyPacket>
+    {
+        let epoch = self.rekey.epoch + 1;
+        let eph = EphemeralPrivateKey::generate(&X25519, &*self.rng)?;
+        let mut public_key = [0_u8; 32];
+        eph.compute_public_key(&mut public_key)?;
+        self.rekey.pending = Some((epoch, eph));
+        let packet = RekeyPacket::new(epoch, public_key);
+        self.rekey.pending_packet = Some((packet.clone(), Instant::now()));
+        Ok(packet)
+    }
+
+    /// The `RekeyPacket` from `begin_rekey()` again, if it has gone unanswered
+    /// for the policy's `retransmit_interval`.  Call this regularly while a
+    /// rekey is pending, and send what it returns.
+    pub fn rekey_retransmit(&mut self) -> Option<RekeyPacket>
+    {
+        let interval = self.rekey.policy.retransmit_interval;
+        match self.rekey.pending_packet {
+            Some((ref packet, ref mut sent)) if sent.elapsed() >= interval => {
+                *sent = Instant::now();
+                Some(packet.clone())
+            },
+            _ => None,
+        }
+    }
+
+    /// Answer a rekey started by the remote.  The returned packet must be sent
+    /// under the current key; we switch to the new key once the remote does.
+    ///
+    /// Returns `None` if both sides started a rekey at the same time and ours
+    /// wins (the one with the greater public key); the remote will answer ours.
+    pub fn accept_rekey(&mut self, packet: &RekeyPacket)
+                        -> Result<Option<RekeyAckPacket>>
+    {
+        // The remote did not get our answer, so give the same one again
+        if let Some((ref answered, ref ack)) = self.rekey.answered {
+            if answered == packet {
+                return Ok(Some(ack.clone()));
+            }
+        }
+
+        if packet.epoch != self.rekey.epoch + 1 {
+            return Err(format!("Rekey for epoch {} received during epoch {}",
+                               packet.epoch, self.rekey.epoch).into());
+        }
+
+        if let Some((_, ref eph)) = self.rekey.pending {
+            let mut our_public_key = [0_u8; 32];
+            eph.compute_public_key(&mut our_public_key)?;
+            if our_public_key > packet.public_key {
+                return Ok(None);
+            }
+        }
+        self.rekey.pending = None;
+        self.rekey.pending_packet = None;
+
+        let eph = EphemeralPrivateKey::generate(&X25519, &*self.rng)?;
+        let mut public_key = [0_u8; 32];
+        eph.compute_public_key(&mut public_key)?;
+
+        let current_key = &self.session_key;
+        let next_key = agree_ephemeral(
+            eph, &X25519, Input::from(&packet.public_key),
+            ErrorKind::Crypto(Unspecified).into(),
+            |secret| derive_next_key(current_key, packet.epoch, secret))?;
+        self.rekey.next_key = Some(next_key);
+
+        let ack = RekeyAckPacket::new(packet.epoch, public_key);
+        self.rekey.answered = Some((packet.clone(), ack.clone()));
+        Ok(Some(ack))
+    }
+
+    /// Finish a rekey we started, switching to the new key immediately.
+    pub fn complete_rekey(&mut self, packet: &RekeyAckPacket) -> Result<()>
+    {
+        let eph = match self.rekey.pending.take() {
+            Some((epoch, eph)) => {
+                if epoch != packet.epoch {
+                    self.rekey.pending = Some((epoch, eph));
+                    return Err(format!("Rekey ack for epoch {} does not match epoch {}",
+                                       packet.epoch, epoch).into());
+                }
+                eph
+            },
+            None => return Err("No rekey in progress.".into()),
+        };
+
+        let new_key = {
+            let old_key = &self.session_key;
+            agree_ephemeral(
+                eph, &X25519, Input::from(&packet.public_key),
+                ErrorKind::Crypto(Unspecified).into(),
+                |secret| derive_next_key(old_key, packet.epoch, secret))?
+        };
+        let old_key = replace(&mut self.session_key, new_key);
+        self.rekey.key_replaced(old_key, false);
+        self.rekey.pending_packet = None;
 
         Ok(())
     }
@@ -344,6 +1070,10 @@ impl Remote {
         }
     }
 
+    /// Verify a server's signature over our challenge nonce alone, which is
+    /// what servers signed before the handshake transcript.  It binds neither
+    /// ephemeral key, so it does not authenticate the session key.
+    #[deprecated(note = "servers now sign the handshake transcript; use complete_handshake()")]
     pub fn validate_nonce_signature(&self, signature: &[u8], server_public_key: &[u8])
                                     -> Result<()>
     {
@@ -353,11 +1083,220 @@ impl Remote {
                                   Input::from(signature))
             .map_err(|_| ErrorKind::RemoteFailedChallenge.into())
     }
+
+    /// Verify the server's signature over the handshake transcript.  This must
+    /// happen before `compute_session_key()`, which consumes the ephemeral key
+    /// the transcript is built from.
+    pub fn validate_handshake_signature(&self, init_ack: &InitAckPacket,
+                                        server_public_key: &[u8], version: u32)
+                                        -> Result<()>
+    {
+        let transcript = self.client_transcript(init_ack, version)?;
+        ::ring::signature::verify(&ED25519,
+                                  Input::from(server_public_key),
+                                  Input::from(&transcript),
+                                  Input::from(&init_ack.get_nonce_response()))
+            .map_err(|_| ErrorKind::RemoteFailedChallenge.into())
+    }
+
+    /// Verify the server's `InitAckPacket` and compute the session key from it,
+    /// switching to the cipher suite the server chose.
+    pub fn complete_handshake(&mut self, init_ack: &InitAckPacket,
+                              server_public_key: &[u8], version: u32)
+                              -> Result<()>
+    {
+        let transcript = self.client_transcript(init_ack, version)?;
+        self.validate_handshake_signature(init_ack, server_public_key, version)?;
+        if !self.cipher_suites.contains(&init_ack.cipher_suite) {
+            return Err(format!("Server chose cipher suite {:?}, which we did not offer",
+                               init_ack.cipher_suite).into());
+        }
+        self.handshake_transcript = Some(transcript);
+        self.compute_session_key(&init_ack.public_key, init_ack.cipher_suite)?;
+        self.connection_id = init_ack.connection_id;
+        Ok(())
+    }
+
+    // The handshake transcript, as the client sees it
+    fn client_transcript(&self, init_ack: &InitAckPacket, version: u32) -> Result<Vec<u8>>
+    {
+        let mut public_key = [0_u8; 32];
+        match self.eph_private_key {
+            Some(ref eph) => eph.compute_public_key(&mut public_key)?,
+            None => return Err("Ephemeral private key already used.".into()),
+        }
+        Ok(handshake_transcript(version, &public_key, &init_ack.public_key, &self.nonce,
+                                &self.cipher_suites, init_ack.cipher_suite))
+    }
+
+    /// Assign a new random connection id to this session.  Done by the server
+    /// while answering the handshake.
+    pub fn assign_connection_id(&mut self) -> Result<()>
+    {
+        let mut bytes: [u8; 8] = [0; 8];
+        while bytes == [0; 8] {
+            self.rng.fill(&mut bytes)?;
+        }
+        self.connection_id = ::bincode::deserialize(&bytes[..])?;
+        Ok(())
+    }
+
+    /// Call with the source address and size of every datagram that opened
+    /// successfully and was not stale (a replayed packet proves nothing about
+    /// its source).  If `from` differs from `addr` (the remote's NAT rebound, or
+    /// it changed networks) this returns a `PathChallengePacket` which must be
+    /// sent to `from`.  Only once the remote answers it from there does `addr`
+    /// change.  Challenges count against the new path's amplification limit, so
+    /// this returns `None` when another would exceed it.
+    pub fn check_source(&mut self, from: SocketAddr, len: usize)
+                        -> Result<Option<PathChallengePacket>>
+    {
+        if from == self.addr {
+            return Ok(None);
+        }
+        let data = match self.pending_path {
+            // Challenge again, in case the first one was lost
+            Some((addr, data)) if addr == from => data,
+            _ => {
+                let mut data: [u8; 8] = [0; 8];
+                self.rng.fill(&mut data)?;
+                self.pending_path = Some((from, data));
+                self.path_bytes_received = 0;
+                self.path_bytes_sent = 0;
+                data
+            },
+        };
+        self.path_bytes_received += len;
+
+        let challenge = PathChallengePacket::new(data);
+        let size = self.datagram_size(
+            C::serialized_size(&(challenge.message_id(), &challenge))?)?;
+        if self.path_bytes_sent + size > self.path_bytes_received * AMPLIFICATION_FACTOR {
+            debug!("Not challenging {} again, which would exceed the amplification limit",
+                   from);
+            return Ok(None);
+        }
+        self.path_bytes_sent += size;
+        Ok(Some(challenge))
+    }
+
+    /// Handle a `PathResponsePacket` received from `from`.  Returns true if it
+    /// answers our outstanding challenge, in which case `addr` is now `from`.
+    pub fn validate_path_response(&mut self, response: &PathResponsePacket,
+                                  from: SocketAddr) -> bool
+    {
+        use ring::constant_time::verify_slices_are_equal;
+
+        match self.pending_path {
+            Some((addr, ref data)) if addr == from
+                && verify_slices_are_equal(&data[..], &response.data[..]).is_ok() => {},
+            _ => return false,
+        }
+        info!("Remote {} moved from {} to {}", self.connection_id, self.addr, from);
+        self.addr = from;
+        self.pending_path = None;
+        self.path_bytes_received = 0;
+        self.path_bytes_sent = 0;
+        true
+    }
+}
+
+#[test]
+fn test_amplification_limit() {
+    use std::str::FromStr;
+    use bincode::serialized_size;
+    use cookie::CookieJar;
+    use packets::{InitPacket, RetryPacket, HeartbeatPacket};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    enum Handshake {
+        Init(Box<InitPacket>),
+        InitAck(InitAckPacket),
+        Retry(RetryPacket),
+        Heartbeat(HeartbeatPacket),
+    }
+    impl Packet for Handshake {
+        fn reply_expected(&self) -> bool { false }
+    }
+
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng.clone()).unwrap();
+
+    // The Init is at least as big as any unauthenticated answer to it
+    let init = InitPacket::new(&mut client).unwrap();
+    let init_ack = InitAckPacket::new(&server, CipherSuite::Aes128Gcm, &[0; 64]).unwrap();
+    let jar = CookieJar::new(&*rng, ::std::time::Duration::from_secs(30)).unwrap();
+    let retry = RetryPacket::new(jar.issue(&addr));
+    assert!(serialized_size(&init).unwrap() >= serialized_size(&init_ack).unwrap());
+    assert!(serialized_size(&init).unwrap() >= serialized_size(&retry).unwrap());
+
+    let mut bytes = client.serialize_packet(&Handshake::Init(Box::new(init.clone())),
+                                            0xABCDE000, 1).unwrap();
+    assert!(client.address_validated);
+    server.deserialize_packet_header::<Handshake>(&mut bytes[..]).unwrap();
+
+    // Answers are allowed up to the amplification factor
+    let init_ack = Handshake::InitAck(init_ack);
+    server.serialize_packet(&init_ack, 0xABCDE000, 1).unwrap();
+    loop {
+        match server.serialize_packet(&init_ack, 0xABCDE000, 1) {
+            Ok(_) => assert!(server.bytes_sent <= bytes.len() * AMPLIFICATION_FACTOR),
+            Err(Error(ErrorKind::AmplificationLimit, _)) => break,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // Once a packet arrives under the session key, the limit no longer applies
+    server.compute_session_key(&init.public_key, CipherSuite::Aes128Gcm).unwrap();
+    client.compute_session_key(&match init_ack {
+        Handshake::InitAck(ref ack) => ack.public_key,
+        _ => unreachable!(),
+    }, CipherSuite::Aes128Gcm).unwrap();
+    let mut bytes = client.serialize_packet(&Handshake::Heartbeat(HeartbeatPacket::new()),
+                                            0xABCDE000, 1).unwrap();
+    server.deserialize_packet_header::<Handshake>(&mut bytes[..]).unwrap();
+    assert!(server.address_validated);
+    server.serialize_packet(&init_ack, 0xABCDE000, 1).unwrap();
 }
 
-fn key_derivation_function(input: &[u8]) -> Result<[u8; 16]>
-{
-    let mut output: [u8; 16] = [0; 16];
-    output.copy_from_slice(&input[0..16]);
-    Ok(output)
+#[test]
+fn test_serialize_packet_into() {
+    use std::str::FromStr;
+    use packets::HeartbeatPacket;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    struct Ping(HeartbeatPacket);
+    impl Packet for Ping {
+        fn reply_expected(&self) -> bool { true }
+    }
+
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng).unwrap();
+    let ping = Ping(HeartbeatPacket::new());
+    let size = client.serialized_packet_size(&ping).unwrap();
+
+    // Too small a buffer is refused without using up a sequence number
+    let mut buf: [u8; MAX_PROTO_PACKET] = [0; MAX_PROTO_PACKET];
+    match client.serialize_packet_into(&ping, 0xABCDE000, 1, &mut buf[..size - 1]) {
+        Err(Error(ErrorKind::BufferTooSmall(needed), _)) => assert_eq!(needed, size),
+        _ => panic!("Expected BufferTooSmall"),
+    }
+    assert_eq!(client.next_local_seq_number, 1);
+
+    let len = client.serialize_packet_into(&ping, 0xABCDE000, 1, &mut buf[..]).unwrap();
+    assert_eq!(len, size);
+    let (body, seq, stale) = server.deserialize_packet_header::<Ping>(&mut buf[..len]).unwrap();
+    assert_eq!(::bincode::deserialize::<Ping>(body).unwrap(), ping);
+    assert_eq!(seq, 1);
+    assert!(!stale);
+
+    // The Vec version writes the same datagram
+    let mut bytes = client.serialize_reply_packet(&ping, 0xABCDE000, 1, seq).unwrap();
+    assert_eq!(bytes.len(), size);
+    let (_, seq, _) = server.deserialize_packet_header::<Ping>(&mut bytes[..]).unwrap();
+    assert_eq!(seq, 2);
 }
@@ -0,0 +1,238 @@
+
+use std::any::Any;
+use std::collections::HashMap;
+use serde::de::DeserializeOwned;
+use errors::*;
+use remote::{Remote, PacketMeta};
+use packets::{Packet, MessageId};
+use codec::{Codec, BincodeCodec};
+
+type Handler<C, P> = Box<dyn FnMut(&mut Remote<C>, P, PacketMeta) -> Result<()>>;
+type Fallback<C> = Box<dyn FnMut(&mut Remote<C>, u16, &[u8], PacketMeta) -> Result<()>>;
+
+// The handlers for one message type
+struct Route<C, P> {
+    any_channel: Option<Handler<C, P>>,
+    channels: HashMap<u8, Handler<C, P>>,
+}
+
+// A `Route<C, P>` with its packet type erased
+trait Dispatch<C> {
+    // Returns false if no handler wanted the message
+    fn dispatch(&mut self, remote: &mut Remote<C>, body: &[u8], meta: PacketMeta)
+                -> Result<bool>;
+    fn as_any(&mut self) -> &mut dyn Any;
+}
+
+impl<C, P> Dispatch<C> for Route<C, P>
+    where C: Codec + 'static,
+          P: Packet + DeserializeOwned + 'static
+{
+    fn dispatch(&mut self, remote: &mut Remote<C>, body: &[u8], meta: PacketMeta)
+                -> Result<bool>
+    {
+        let packet: P = C::deserialize(body)?;
+        let handler = match self.channels.get_mut(&packet.channel()) {
+            Some(handler) => handler,
+            None => match self.any_channel {
+                Some(ref mut handler) => handler,
+                None => return Ok(false),
+            },
+        };
+        handler(remote, packet, meta)?;
+        Ok(true)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any
+    {
+        self
+    }
+}
+
+/// Calls a handler for each packet received, chosen by its message id (and
+/// optionally its channel).  The packets must have been sent in an `Envelope`,
+/// which puts the message id in front of each of them.  Packets are decoded
+/// with the remote's codec `C`.
+///
+/// ```ignore
+/// let mut router = Router::new();
+/// router.on(|remote, _: HeartbeatPacket, meta| { ... })?
+///       .on_channel(2, |remote, chat: ChatPacket, meta| { ... })?
+///       .fallback(|remote, id, body, meta| { ... });
+/// router.dispatch(&mut remote, &mut datagram)?;
+/// ```
+pub struct Router<C = BincodeCodec> {
+    routes: HashMap<u16, Box<dyn Dispatch<C>>>,
+    fallback: Option<Fallback<C>>,
+}
+
+impl Router {
+    pub fn new() -> Router
+    {
+        Router::default()
+    }
+}
+
+// For remotes using a codec other than the default
+impl<C> Default for Router<C> {
+    fn default() -> Router<C>
+    {
+        Router {
+            routes: HashMap::new(),
+            fallback: None,
+        }
+    }
+}
+
+impl<C: Codec + 'static> Router<C> {
+    /// Handle packets of type `P`, on any channel which has no handler of its
+    /// own.  Fails if another type is already routed under `P`'s message id.
+    pub fn on<P, F>(&mut self, handler: F) -> Result<&mut Router<C>>
+        where P: Packet + MessageId + DeserializeOwned + 'static,
+              F: FnMut(&mut Remote<C>, P, PacketMeta) -> Result<()> + 'static
+    {
+        self.route::<P>()?.any_channel = Some(Box::new(handler));
+        Ok(self)
+    }
+
+    /// Handle packets of type `P` sent on `channel`.  Fails if another type is
+    /// already routed under `P`'s message id.
+    pub fn on_channel<P, F>(&mut self, channel: u8, handler: F) -> Result<&mut Router<C>>
+        where P: Packet + MessageId + DeserializeOwned + 'static,
+              F: FnMut(&mut Remote<C>, P, PacketMeta) -> Result<()> + 'static
+    {
+        self.route::<P>()?.channels.insert(channel, Box::new(handler));
+        Ok(self)
+    }
+
+    /// Handle packets which no other handler wants.  This is given the message
+    /// id and the serialized packet.  Without a fallback such packets are
+    /// dropped.
+    pub fn fallback<F>(&mut self, handler: F) -> &mut Router<C>
+        where F: FnMut(&mut Remote<C>, u16, &[u8], PacketMeta) -> Result<()> + 'static
+    {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    fn route<P>(&mut self) -> Result<&mut Route<C, P>>
+        where P: Packet + MessageId + DeserializeOwned + 'static
+    {
+        let route = self.routes.entry(P::MESSAGE_ID).or_insert_with(|| Box::new(Route::<C, P> {
+            any_channel: None,
+            channels: HashMap::new(),
+        }));
+        match route.as_any().downcast_mut::<Route<C, P>>() {
+            Some(route) => Ok(route),
+            None => Err(format!("Message id {} is already routed to another type",
+                                P::MESSAGE_ID).into()),
+        }
+    }
+
+    /// Open a datagram from `remote` and pass the packet in it to its handler
+    pub fn dispatch(&mut self, remote: &mut Remote<C>, bytes: &mut [u8]) -> Result<()>
+    {
+        let (body, meta) = remote.open_datagram(bytes)?;
+        let id: u16 = C::deserialize(body)?;
+        let body = &body[C::serialized_size(&id)?..];
+
+        if let Some(route) = self.routes.get_mut(&id) {
+            if route.dispatch(remote, body, meta)? {
+                return Ok(());
+            }
+        }
+        match self.fallback {
+            Some(ref mut fallback) => fallback(remote, id, body, meta),
+            None => {
+                debug!("Dropping message {} which has no handler", id);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::net::SocketAddr;
+    use ring::rand::SystemRandom;
+    use packets::{Envelope, ControlPacket, HeartbeatPacket, MessageSet};
+
+    // The channel of a chat message is picked by its sender
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    struct ChatPacket {
+        channel: u8,
+        text: String,
+    }
+    impl MessageId for ChatPacket {
+        const MESSAGE_ID: u16 = 300;
+    }
+    impl Packet for ChatPacket {
+        fn reply_expected(&self) -> bool { false }
+        fn message_id(&self) -> u16 { 300 }
+        fn channel(&self) -> u8 { self.channel }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    #[packet(id = 301)]
+    struct EmotePacket;
+
+    #[derive(Debug, PartialEq, Clone, Packet, MessageSet)]
+    enum Game {
+        Chat(ChatPacket),
+        Emote(EmotePacket),
+    }
+
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut client = Remote::new(addr, rng.clone()).unwrap();
+    let mut server = Remote::new(addr, rng).unwrap();
+
+    let log: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let mut router = Router::new();
+    {
+        let (l1, l2, l3, l4) = (log.clone(), log.clone(), log.clone(), log.clone());
+        router
+            .on(move |_, _: HeartbeatPacket, _| {
+                l1.borrow_mut().push("heartbeat".to_owned());
+                Ok(())
+            }).unwrap()
+            .on(move |_, chat: ChatPacket, _| {
+                l2.borrow_mut().push(format!("chat {}", chat.text));
+                Ok(())
+            }).unwrap()
+            .on_channel(7, move |_, chat: ChatPacket, meta| {
+                assert!(!meta.stale);
+                l3.borrow_mut().push(format!("team chat {}", chat.text));
+                Ok(())
+            }).unwrap()
+            .fallback(move |_, id, _, _| {
+                l4.borrow_mut().push(format!("unknown {}", id));
+                Ok(())
+            });
+    }
+
+    let chat = |channel: u8, text: &str| Envelope::Message(Game::Chat(ChatPacket {
+        channel: channel,
+        text: text.to_owned(),
+    }));
+    for envelope in [Envelope::Control(ControlPacket::Heartbeat(HeartbeatPacket::new())),
+                     chat(0, "hello"),
+                     chat(7, "rush b"),
+                     Envelope::Message(Game::Emote(EmotePacket))]
+    {
+        let mut bytes = client.serialize_packet(&envelope, 0xABCDE000, 1).unwrap();
+        router.dispatch(&mut server, &mut bytes[..]).unwrap();
+    }
+    assert_eq!(*log.borrow(), vec!["heartbeat", "chat hello", "team chat rush b", "unknown 301"]);
+
+    // Another type under a message id already routed is refused
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    #[packet(id = 300)]
+    struct ImpostorPacket;
+    assert!(router.on(|_, _: ImpostorPacket, _| Ok(())).is_err());
+    assert!(router.on_channel(1, |_, _: ImpostorPacket, _| Ok(())).is_err());
+}
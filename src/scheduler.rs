@@ -0,0 +1,281 @@
+
+use std::collections::HashMap;
+use errors::*;
+
+/// What to do with a scheduled message which did not fit in this tick's budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IfUnsent {
+    /// Keep it for the next tick, its priority still accumulating
+    Defer,
+    /// Forget it, such as for state which will soon be sent again anyway
+    Drop,
+}
+
+pub(crate) struct Entry {
+    pub(crate) body: Vec<u8>,
+    pub(crate) reply_expected: bool,
+    priority: f32,
+    accumulated: f32,
+    if_unsent: IfUnsent,
+}
+
+/// The outbound messages waiting on a `Remote`, sent by priority within a
+/// per-tick budget of bytes (see `Remote::schedule()` and
+/// `Remote::send_scheduled()`).
+///
+/// Each message has a key, such as the entity it describes; scheduling
+/// under the same key again replaces the message but keeps the priority it
+/// has accumulated.  Every tick a waiting message's priority is added to
+/// its accumulated priority, so that even unimportant messages get sent
+/// eventually.  Messages should be well under the budget; one larger than
+/// it is dropped.
+pub struct Scheduler {
+    entries: HashMap<u64, Entry>,
+    budget: usize,
+}
+
+impl Scheduler {
+    /// A scheduler which sends up to `budget` bytes (of datagrams) per tick
+    pub fn new(budget: usize) -> Scheduler
+    {
+        Scheduler {
+            entries: HashMap::new(),
+            budget: budget,
+        }
+    }
+
+    pub fn budget(&self) -> usize
+    {
+        self.budget
+    }
+
+    pub fn set_budget(&mut self, budget: usize)
+    {
+        self.budget = budget;
+    }
+
+    /// Set the budget from a bandwidth in bytes per second, and the number
+    /// of ticks per second (which must not be zero)
+    pub fn set_bandwidth(&mut self, bytes_per_second: usize, ticks_per_second: usize)
+                         -> Result<()>
+    {
+        if ticks_per_second == 0 {
+            return Err("The tick rate must not be zero.".into());
+        }
+        self.budget = bytes_per_second / ticks_per_second;
+        Ok(())
+    }
+
+    /// The number of messages waiting
+    pub fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.entries.is_empty()
+    }
+
+    /// The accumulated priority of the message waiting under `key`
+    pub fn accumulated(&self, key: u64) -> Option<f32>
+    {
+        self.entries.get(&key).map(|entry| entry.accumulated)
+    }
+
+    /// Queue an encoded body under `key`, replacing any waiting there.  The
+    /// priority must be finite and not negative.
+    pub fn insert(&mut self, key: u64, body: Vec<u8>, reply_expected: bool, priority: f32,
+                  if_unsent: IfUnsent)
+                  -> Result<()>
+    {
+        if !priority.is_finite() || priority < 0.0 {
+            return Err("Priority must be finite and not negative.".into());
+        }
+        let accumulated = self.entries.get(&key).map_or(0.0, |entry| entry.accumulated);
+        self.entries.insert(key, Entry {
+            body: body,
+            reply_expected: reply_expected,
+            priority: priority,
+            accumulated: accumulated,
+            if_unsent: if_unsent,
+        });
+        Ok(())
+    }
+
+    /// Forget the message waiting under `key`
+    pub fn remove(&mut self, key: u64) -> bool
+    {
+        self.entries.remove(&key).is_some()
+    }
+
+    /// Pick this tick's messages: the highest accumulated priorities whose
+    /// datagrams (each `overhead` bytes more than its body) fit in the
+    /// budget.  The rest are deferred or dropped, as are those which could
+    /// never fit.  Returns the messages to send, most important first; any
+    /// which cannot be sent should be given back with `restore()`.
+    pub(crate) fn tick(&mut self, overhead: usize) -> Vec<(u64, Entry)>
+    {
+        let budget = self.budget;
+        self.entries.retain(|key, entry| {
+            if overhead + entry.body.len() > budget {
+                debug!("Dropping scheduled message {}, which is larger than the budget", key);
+                return false;
+            }
+            entry.accumulated += entry.priority;
+            true
+        });
+
+        let mut keys: Vec<(u64, f32)> = self.entries.iter()
+            .map(|(key, entry)| (*key, entry.accumulated))
+            .collect();
+        keys.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let mut remaining = self.budget;
+        let mut sent = Vec::new();
+        for (key, _) in keys {
+            let size = overhead + self.entries[&key].body.len();
+            if size <= remaining {
+                remaining -= size;
+                let entry = self.entries.remove(&key).unwrap();
+                sent.push((key, entry));
+            }
+        }
+        self.entries.retain(|_, entry| entry.if_unsent == IfUnsent::Defer);
+        sent
+    }
+
+    /// Put back a message from `tick()` which could not be sent, unless
+    /// another has been scheduled under its key since
+    pub(crate) fn restore(&mut self, key: u64, entry: Entry)
+    {
+        self.entries.entry(key).or_insert(entry);
+    }
+}
+
+impl Default for Scheduler {
+    /// An unlimited budget
+    fn default() -> Scheduler
+    {
+        Scheduler::new(usize::MAX)
+    }
+}
+
+#[test]
+fn test() {
+    use std::io;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::net::SocketAddr;
+    use ring::rand::SystemRandom;
+    use remote::Remote;
+    use packets::Packet;
+    use pool::BufferPool;
+    use sender::{BatchSender, DatagramSink};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Packet)]
+    #[packet(id = 310)]
+    struct MovePacket {
+        entity: u32,
+        position: [f32; 3],
+    }
+
+    struct Sink(Vec<Vec<u8>>);
+    impl DatagramSink for Sink {
+        fn send_to(&mut self, datagram: &[u8], _: SocketAddr) -> io::Result<usize>
+        {
+            self.0.push(datagram.to_vec());
+            Ok(datagram.len())
+        }
+    }
+
+    let rng = Arc::new(SystemRandom::new());
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut server = Remote::new(addr, rng.clone()).unwrap();
+    let mut client = Remote::new(addr, rng).unwrap();
+    let pool = BufferPool::new(32);
+    let mut sender = BatchSender::new(Sink(Vec::new()), 64, 64);
+    let moved = |entity: u32| MovePacket { entity: entity, position: [entity as f32; 3] };
+
+    // Room for two datagrams a tick
+    let size = server.serialized_packet_size(&moved(0)).unwrap();
+    server.scheduler.set_budget(size * 2 + size / 2);
+
+    // The nearby player (1) is more important than those far away
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        server.schedule(1, &moved(1), 10.0, IfUnsent::Defer).unwrap();
+        server.schedule(2, &moved(2), 3.0, IfUnsent::Defer).unwrap();
+        server.schedule(3, &moved(3), 2.0, IfUnsent::Defer).unwrap();
+        server.schedule(4, &moved(4), 1.0, IfUnsent::Drop).unwrap();
+        assert_eq!(server.send_scheduled(0xABCDE000, 1, &pool, &mut sender).unwrap(), 2);
+        sender.flush().unwrap();
+        let mut tick = Vec::new();
+        for mut bytes in sender.sink_mut().0.drain(..) {
+            let (packet, _) = client.deserialize_packet::<MovePacket>(&mut bytes[..]).unwrap();
+            tick.push(packet.entity);
+        }
+        received.push(tick);
+    }
+    // Player 3 waited, accumulating priority, until it beat player 2
+    assert_eq!(received, vec![vec![1, 2], vec![1, 3], vec![1, 2]]);
+    assert_eq!(server.scheduler.len(), 1);
+    assert_eq!(server.scheduler.accumulated(3), Some(2.0));
+    assert_eq!(server.scheduler.accumulated(4), None);
+
+    // Spending less than the budget never defers anything
+    assert!(server.scheduler.set_bandwidth(1_000_000, 0).is_err());
+    server.scheduler.set_bandwidth(1_000_000, 20).unwrap();
+    assert_eq!(server.scheduler.budget(), 50_000);
+    for entity in 1..20 {
+        server.schedule(entity, &moved(entity as u32), 1.0, IfUnsent::Drop).unwrap();
+    }
+    assert_eq!(server.send_scheduled(0xABCDE000, 1, &pool, &mut sender).unwrap(), 19);
+    assert!(server.scheduler.is_empty());
+
+    // What cannot be sealed stays scheduled
+    server.schedule(1, &moved(1), 1.0, IfUnsent::Drop).unwrap();
+    server.schedule(2, &moved(2), 1.0, IfUnsent::Drop).unwrap();
+    assert!(server.send_scheduled(0xABCDE000, 1, &BufferPool::new(0), &mut sender).is_err());
+    assert_eq!(server.scheduler.len(), 2);
+    assert_eq!(server.send_scheduled(0xABCDE000, 1, &pool, &mut sender).unwrap(), 2);
+
+    // A failure sending a full batch does not keep the messages in it, which
+    // were queued.  When the queue is full, those not queued stay scheduled.
+    struct Broken(io::ErrorKind);
+    impl DatagramSink for Broken {
+        fn send_to(&mut self, _: &[u8], _: SocketAddr) -> io::Result<usize>
+        {
+            Err(io::Error::new(self.0, "broken"))
+        }
+    }
+    let mut broken = BatchSender::new(Broken(io::ErrorKind::ConnectionRefused), 1, 1);
+    server.schedule(1, &moved(1), 1.0, IfUnsent::Defer).unwrap();
+    server.schedule(2, &moved(2), 1.0, IfUnsent::Defer).unwrap();
+    assert_eq!(server.send_scheduled(0xABCDE000, 1, &pool, &mut broken).unwrap(), 2);
+    assert!(server.scheduler.is_empty());
+    assert_eq!(broken.pending(), 0);
+
+    let mut blocked = BatchSender::new(Broken(io::ErrorKind::WouldBlock), 1, 1);
+    server.schedule(1, &moved(1), 1.0, IfUnsent::Defer).unwrap();
+    server.schedule(2, &moved(2), 1.0, IfUnsent::Defer).unwrap();
+    assert!(server.send_scheduled(0xABCDE000, 1, &pool, &mut blocked).is_err());
+    assert_eq!(blocked.pending(), 1);
+    assert_eq!(server.scheduler.len(), 1);
+
+    // Priorities must be sane
+    assert!(server.schedule(5, &moved(5), f32::NAN, IfUnsent::Defer).is_err());
+    assert!(server.schedule(5, &moved(5), f32::INFINITY, IfUnsent::Defer).is_err());
+    assert!(server.schedule(5, &moved(5), -1.0, IfUnsent::Defer).is_err());
+    assert_eq!(server.scheduler.accumulated(5), None);
+
+    // A message which could never fit is refused, or dropped if the budget
+    // shrinks under it
+    server.scheduler.set_budget(size - 1);
+    assert!(server.schedule(1, &moved(1), 1.0, IfUnsent::Defer).is_err());
+    server.scheduler.set_budget(size);
+    server.schedule(1, &moved(1), 1.0, IfUnsent::Defer).unwrap();
+    server.scheduler.set_budget(size - 1);
+    assert_eq!(server.send_scheduled(0xABCDE000, 1, &pool, &mut sender).unwrap(), 0);
+    assert!(server.scheduler.is_empty());
+}
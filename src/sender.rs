@@ -0,0 +1,226 @@
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use errors::*;
+use pool::PooledBuffer;
+
+/// Somewhere datagrams can be sent, such as a `UdpSocket`
+pub trait DatagramSink {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Send as many of `datagrams` as possible, in order, and return how many
+    /// were sent.  Like `sendmmsg()`, an error is only returned if the first
+    /// one could not be sent.  Override this where the platform can send a
+    /// batch in one system call.
+    fn send_batch(&mut self, datagrams: &[(SocketAddr, PooledBuffer)]) -> io::Result<usize>
+    {
+        for (sent, &(addr, ref datagram)) in datagrams.iter().enumerate() {
+            if let Err(e) = self.send_to(datagram, addr) {
+                if sent == 0 { return Err(e); }
+                return Ok(sent);
+            }
+        }
+        Ok(datagrams.len())
+    }
+}
+
+impl DatagramSink for UdpSocket {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize>
+    {
+        UdpSocket::send_to(self, datagram, addr)
+    }
+}
+
+impl DatagramSink for &UdpSocket {
+    fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize>
+    {
+        UdpSocket::send_to(self, datagram, addr)
+    }
+}
+
+/// Queues datagrams and hands them to a `DatagramSink` in batches of
+/// `batch_size`, or when `flush()` is called (once per tick, say).
+///
+/// If the sink would block, the datagrams not yet sent stay queued for the
+/// next flush, up to `max_queued` of them.
+pub struct BatchSender<S> {
+    sink: S,
+    queue: Vec<(SocketAddr, PooledBuffer)>,
+    batch_size: usize,
+    max_queued: usize,
+}
+
+impl<S: DatagramSink> BatchSender<S> {
+    /// `max_queued` must be at least `batch_size`
+    pub fn new(sink: S, batch_size: usize, max_queued: usize) -> BatchSender<S>
+    {
+        assert!(batch_size > 0);
+        assert!(max_queued >= batch_size);
+        BatchSender {
+            sink: sink,
+            queue: Vec::with_capacity(batch_size),
+            batch_size: batch_size,
+            max_queued: max_queued,
+        }
+    }
+
+    pub fn sink(&self) -> &S
+    {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S
+    {
+        &mut self.sink
+    }
+
+    /// The number of datagrams queued and not yet sent
+    pub fn pending(&self) -> usize
+    {
+        self.queue.len()
+    }
+
+    /// Queue `datagram` for `addr`, sending the batch if it is full.  Only
+    /// fails if the datagram was not queued: when `max_queued` datagrams are
+    /// already waiting (the sink has been blocking) it is dropped, with
+    /// `SendQueueFull`.  An error sending the batch is logged, as it concerns
+    /// a datagram queued earlier (see `flush()`).
+    pub fn push(&mut self, addr: SocketAddr, datagram: PooledBuffer) -> Result<()>
+    {
+        if self.queue.len() >= self.max_queued {
+            return Err(ErrorKind::SendQueueFull.into());
+        }
+        self.queue.push((addr, datagram));
+        if self.queue.len() >= self.batch_size {
+            if let Err(e) = self.flush() {
+                warn!("Dropped a queued datagram: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send everything queued.  Returns the number of datagrams sent.  If the
+    /// sink fails (other than by blocking), the oldest datagram still queued
+    /// (the first of the batch, which it failed on) is dropped, and the error
+    /// returned; the rest stay queued.
+    pub fn flush(&mut self) -> Result<usize>
+    {
+        let mut total = 0;
+        while !self.queue.is_empty() {
+            let end = ::std::cmp::min(self.queue.len(), self.batch_size);
+            match self.sink.send_batch(&self.queue[..end]) {
+                Ok(0) => break,
+                Ok(sent) => {
+                    self.queue.drain(..sent);
+                    total += sent;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    self.queue.remove(0);
+                    return Err(e.into());
+                },
+            }
+        }
+        Ok(total)
+    }
+}
+
+#[test]
+fn test() {
+    use std::str::FromStr;
+    use pool::BufferPool;
+
+    // Takes `room` datagrams, then blocks
+    struct Sink {
+        sent: Vec<(SocketAddr, Vec<u8>)>,
+        batches: usize,
+        room: usize,
+    }
+    impl DatagramSink for Sink {
+        fn send_to(&mut self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize>
+        {
+            if self.room == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "full"));
+            }
+            if datagram.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty"));
+            }
+            self.room -= 1;
+            self.sent.push((addr, datagram.to_vec()));
+            Ok(datagram.len())
+        }
+
+        fn send_batch(&mut self, datagrams: &[(SocketAddr, PooledBuffer)]) -> io::Result<usize>
+        {
+            self.batches += 1;
+            let mut sent = 0;
+            for &(addr, ref datagram) in datagrams {
+                match self.send_to(datagram, addr) {
+                    Ok(_) => sent += 1,
+                    Err(e) => if sent == 0 { return Err(e) } else { break },
+                }
+            }
+            Ok(sent)
+        }
+    }
+
+    let pool = BufferPool::new(16);
+    let datagram = |bytes: &[u8]| {
+        let mut buf = pool.acquire().unwrap();
+        buf[..bytes.len()].copy_from_slice(bytes);
+        buf.set_len(bytes.len());
+        buf
+    };
+    let addr: SocketAddr = FromStr::from_str("10.1.2.3:5555").unwrap();
+    let mut sender = BatchSender::new(Sink { sent: Vec::new(), batches: 0, room: 5 }, 4, 6);
+
+    // A full batch is sent straight away
+    for i in 0..6 {
+        sender.push(addr, datagram(&[i])).unwrap();
+    }
+    assert_eq!(sender.sink().sent.len(), 4);
+    assert_eq!(sender.sink().batches, 1);
+    assert_eq!(sender.pending(), 2);
+
+    // What would block stays queued, up to a limit
+    assert_eq!(sender.flush().unwrap(), 1);
+    assert_eq!(sender.pending(), 1);
+    for i in 6..11 {
+        sender.push(addr, datagram(&[i])).unwrap();
+    }
+    match sender.push(addr, datagram(&[11])) {
+        Err(Error(ErrorKind::SendQueueFull, _)) => {},
+        _ => panic!("Expected SendQueueFull"),
+    }
+    assert_eq!(sender.pending(), 6);
+    assert_eq!(pool.stats().in_use, 6);
+    sender.sink_mut().room = 10;
+    assert_eq!(sender.flush().unwrap(), 6);
+    let sent: Vec<u8> = sender.sink().sent.iter().map(|(_, d)| d[0]).collect();
+    assert_eq!(sent, (0..11).collect::<Vec<u8>>());
+    assert_eq!(pool.stats().in_use, 0);
+
+    // A datagram which fails is dropped
+    sender.push(addr, datagram(&[])).unwrap();
+    sender.push(addr, datagram(&[12])).unwrap();
+    assert!(sender.flush().is_err());
+    assert_eq!(sender.flush().unwrap(), 1);
+    assert_eq!(sender.pending(), 0);
+
+    // A failure sending the batch does not fail the push which filled it
+    let mut sender = BatchSender::new(Sink { sent: Vec::new(), batches: 0, room: 5 }, 1, 4);
+    sender.push(addr, datagram(&[])).unwrap();
+    sender.push(addr, datagram(&[13])).unwrap();
+    assert_eq!(sender.pending(), 0);
+    assert_eq!(sender.sink().sent.len(), 1);
+
+    // Through a real socket
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let to = socket.local_addr().unwrap();
+    let mut sender = BatchSender::new(&socket, 8, 8);
+    sender.push(to, datagram(b"hello")).unwrap();
+    assert_eq!(sender.flush().unwrap(), 1);
+    let mut buf = [0; 16];
+    let (len, _) = socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"hello");
+}
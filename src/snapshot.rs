@@ -0,0 +1,250 @@
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use errors::*;
+
+/// The state of every replicated entity at one tick
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot<E> {
+    pub tick: u32,
+    pub entities: BTreeMap<u32, E>,
+}
+
+impl<E> Snapshot<E> {
+    pub fn new(tick: u32) -> Snapshot<E>
+    {
+        Snapshot {
+            tick: tick,
+            entities: BTreeMap::new(),
+        }
+    }
+}
+
+/// A snapshot as sent to one client: the entities which changed since a
+/// baseline snapshot the client acknowledged, and those which are gone.
+/// Without a baseline it holds every entity.  Send it inside one of your own
+/// messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotDelta<E> {
+    pub tick: u32,
+    pub baseline: Option<u32>,
+    pub changed: Vec<(u32, E)>,
+    pub removed: Vec<u32>,
+}
+
+// Whether tick `a` is after tick `b`, allowing for wrap around
+fn is_after(a: u32, b: u32) -> bool
+{
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// The server's side of snapshot replication, kept for each `Remote`.  It
+/// remembers the last `capacity` snapshots sent, so that each new one can be
+/// sent as a delta against the latest the client acknowledged.
+///
+/// Snapshots are shared (`Arc`) so that the server builds each tick's snapshot
+/// once for all of its clients.
+pub struct SnapshotSender<E> {
+    history: VecDeque<Arc<Snapshot<E>>>,
+    capacity: usize,
+    acked: Option<u32>,
+}
+
+impl<E: Clone + PartialEq> SnapshotSender<E> {
+    pub fn new(capacity: usize) -> SnapshotSender<E>
+    {
+        assert!(capacity > 0);
+        SnapshotSender {
+            history: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+            acked: None,
+        }
+    }
+
+    /// The latest tick the client acknowledged
+    pub fn acked(&self) -> Option<u32>
+    {
+        self.acked
+    }
+
+    /// Note that the client received the snapshot for `tick`.  Older
+    /// acknowledgements arriving late are ignored.
+    pub fn ack(&mut self, tick: u32)
+    {
+        match self.acked {
+            Some(acked) if !is_after(tick, acked) => {},
+            _ => self.acked = Some(tick),
+        }
+    }
+
+    /// Encode `snapshot` for the client, and remember it as a possible
+    /// baseline.  If the acknowledged snapshot has been forgotten (or there is
+    /// none) every entity is sent.
+    pub fn encode(&mut self, snapshot: Arc<Snapshot<E>>) -> SnapshotDelta<E>
+    {
+        let baseline = match self.acked {
+            Some(acked) => self.history.iter().find(|s| s.tick == acked).cloned(),
+            None => None,
+        };
+
+        let delta = match baseline {
+            Some(ref baseline) => SnapshotDelta {
+                tick: snapshot.tick,
+                baseline: Some(baseline.tick),
+                changed: snapshot.entities.iter()
+                    .filter(|&(id, state)| baseline.entities.get(id) != Some(state))
+                    .map(|(id, state)| (*id, state.clone()))
+                    .collect(),
+                removed: baseline.entities.keys()
+                    .filter(|id| !snapshot.entities.contains_key(id))
+                    .cloned()
+                    .collect(),
+            },
+            None => SnapshotDelta {
+                tick: snapshot.tick,
+                baseline: None,
+                changed: snapshot.entities.iter().map(|(id, state)| (*id, state.clone())).collect(),
+                removed: Vec::new(),
+            },
+        };
+
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(snapshot);
+        delta
+    }
+}
+
+/// The client's side of snapshot replication.  It rebuilds full snapshots
+/// from deltas, keeping the last `capacity` as baselines.  Acknowledge each
+/// snapshot it returns to the server.
+pub struct SnapshotReceiver<E> {
+    history: VecDeque<Arc<Snapshot<E>>>,
+    capacity: usize,
+}
+
+impl<E: Clone> SnapshotReceiver<E> {
+    /// `capacity` should be at least the server's `SnapshotSender` capacity
+    pub fn new(capacity: usize) -> SnapshotReceiver<E>
+    {
+        assert!(capacity > 0);
+        SnapshotReceiver {
+            history: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+        }
+    }
+
+    /// The newest snapshot received
+    pub fn latest(&self) -> Option<&Arc<Snapshot<E>>>
+    {
+        self.history.back()
+    }
+
+    /// Rebuild the snapshot a delta describes.  Returns `None` for a delta
+    /// older than the latest snapshot (it arrived out of order), and fails if
+    /// its baseline has been forgotten.
+    pub fn apply(&mut self, delta: SnapshotDelta<E>) -> Result<Option<Arc<Snapshot<E>>>>
+    {
+        if let Some(latest) = self.history.back() {
+            if !is_after(delta.tick, latest.tick) { return Ok(None); }
+        }
+
+        let mut entities = match delta.baseline {
+            Some(tick) => match self.history.iter().find(|s| s.tick == tick) {
+                Some(baseline) => baseline.entities.clone(),
+                None => return Err(format!("Snapshot baseline {} is gone.", tick).into()),
+            },
+            None => BTreeMap::new(),
+        };
+        for id in &delta.removed {
+            entities.remove(id);
+        }
+        entities.extend(delta.changed);
+
+        let snapshot = Arc::new(Snapshot { tick: delta.tick, entities: entities });
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(snapshot.clone());
+        Ok(Some(snapshot))
+    }
+}
+
+#[test]
+fn test() {
+    use codec::{Codec, BincodeCodec};
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Player {
+        x: i32,
+        y: i32,
+        health: u8,
+    }
+
+    let mut world: Snapshot<Player> = Snapshot::new(0);
+    for id in 0..50 {
+        world.entities.insert(id, Player { x: id as i32, y: 0, health: 100 });
+    }
+
+    let mut sender = SnapshotSender::new(8);
+    let mut receiver = SnapshotReceiver::new(8);
+    let send = |sender: &mut SnapshotSender<Player>, world: &Snapshot<Player>| {
+        let delta = sender.encode(Arc::new(world.clone()));
+        // Through the wire
+        let bytes = ::bincode::serialize(&delta).unwrap();
+        (BincodeCodec::deserialize::<SnapshotDelta<Player>>(&bytes).unwrap(), bytes.len())
+    };
+
+    // Nothing acknowledged yet, so everything is sent
+    world.tick = 1;
+    let (delta, full_size) = send(&mut sender, &world);
+    assert_eq!(delta.baseline, None);
+    assert_eq!(delta.changed.len(), 50);
+    let snapshot = receiver.apply(delta).unwrap().unwrap();
+    assert_eq!(*snapshot, world);
+    sender.ack(snapshot.tick);
+
+    // One player moves, one leaves and one joins
+    world.tick = 2;
+    world.entities.get_mut(&7).unwrap().x = 70;
+    world.entities.remove(&8);
+    world.entities.insert(100, Player { x: 0, y: 0, health: 50 });
+    let (delta, size) = send(&mut sender, &world);
+    assert_eq!(delta.baseline, Some(1));
+    assert_eq!(delta.changed.len(), 2);
+    assert_eq!(delta.removed, vec![8]);
+    assert!(size * 10 < full_size);
+
+    // That one is lost, so the next is still against tick 1
+    world.tick = 3;
+    world.entities.get_mut(&9).unwrap().health = 0;
+    let (delta, _) = send(&mut sender, &world);
+    assert_eq!(delta.baseline, Some(1));
+    assert_eq!(delta.changed.len(), 3);
+    let snapshot = receiver.apply(delta.clone()).unwrap().unwrap();
+    assert_eq!(*snapshot, world);
+    sender.ack(3);
+    sender.ack(2); // late, and ignored
+    assert_eq!(sender.acked(), Some(3));
+
+    // Duplicates are ignored
+    assert!(receiver.apply(delta).unwrap().is_none());
+
+    // Once the acknowledged snapshot is forgotten, everything is sent again
+    for tick in 4..20 {
+        world.tick = tick;
+        let (delta, _) = send(&mut sender, &world);
+        assert_eq!(delta.baseline, if tick <= 11 { Some(3) } else { None });
+    }
+
+    // A receiver which has forgotten the baseline fails
+    let mut forgetful = SnapshotReceiver::new(1);
+    forgetful.apply(SnapshotDelta { tick: 1, baseline: None, changed: vec![], removed: vec![] })
+        .unwrap();
+    forgetful.apply(SnapshotDelta { tick: 2, baseline: None, changed: vec![], removed: vec![] })
+        .unwrap();
+    let delta: SnapshotDelta<Player> =
+        SnapshotDelta { tick: 3, baseline: Some(1), changed: vec![], removed: vec![] };
+    assert!(forgetful.apply(delta).is_err());
+}
@@ -0,0 +1,141 @@
+
+use errors::*;
+use ring::aead::{CHACHA20_POLY1305, SealingKey, OpeningKey, seal_in_place, open_in_place};
+use ring::rand::SecureRandom;
+use cookie::unix_time;
+
+// The most user data a connect token may carry
+pub const MAX_TOKEN_USER_DATA: usize = 256;
+
+const TOKEN_AD: &[u8] = b"siege-net connect token";
+
+/// Authorization for a player to connect to a server.  It is issued by the
+/// login backend, sealed so that only servers sharing the backend's key can
+/// read it, and passed to the server by the client (which cannot read or
+/// alter it) in its `ConnectPacket`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ConnectToken {
+    /// The player this token was issued to
+    pub player_id: u64,
+
+    /// When the token expires, in seconds since the unix epoch
+    pub expires: u64,
+
+    /// The server this token may be used on
+    pub server_id: u64,
+
+    /// Anything else the backend wants to tell the server (at most
+    /// `MAX_TOKEN_USER_DATA` bytes)
+    pub user_data: Vec<u8>,
+}
+
+/// A `ConnectToken` as the client sees it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct SealedConnectToken(pub Vec<u8>);
+
+/// The key shared by the login backend and the servers, used to seal and open
+/// connect tokens.
+pub struct ConnectTokenKey {
+    sealing_key: SealingKey,
+    opening_key: OpeningKey,
+}
+
+impl ConnectTokenKey {
+    /// `key` must be 32 bytes
+    pub fn new(key: &[u8]) -> Result<ConnectTokenKey>
+    {
+        Ok(ConnectTokenKey {
+            sealing_key: SealingKey::new(&CHACHA20_POLY1305, key)?,
+            opening_key: OpeningKey::new(&CHACHA20_POLY1305, key)?,
+        })
+    }
+
+    /// Seal a token (this is done by the login backend)
+    pub fn seal(&self, token: &ConnectToken, rng: &dyn SecureRandom)
+                -> Result<SealedConnectToken>
+    {
+        use bincode::serialize;
+
+        if token.user_data.len() > MAX_TOKEN_USER_DATA {
+            return Err(ErrorKind::InvalidConnectToken("Too much user data".to_owned()).into());
+        }
+
+        let tag_len = CHACHA20_POLY1305.tag_len();
+        let mut bytes: Vec<u8> = vec![0; 12];
+        rng.fill(&mut bytes[..12])?;
+        bytes.extend_from_slice(&serialize(token)?);
+        bytes.resize(bytes.len() + tag_len, 0);
+        let (nonce, in_out) = bytes.split_at_mut(12);
+        let size = seal_in_place(&self.sealing_key, nonce, TOKEN_AD, in_out, tag_len)?;
+        bytes.truncate(12 + size);
+        Ok(SealedConnectToken(bytes))
+    }
+
+    /// Open a token, without checking whether it applies
+    pub fn open(&self, sealed: &SealedConnectToken) -> Result<ConnectToken>
+    {
+        use bincode::deserialize;
+
+        if sealed.0.len() < 12 {
+            return Err(ErrorKind::InvalidConnectToken("Truncated".to_owned()).into());
+        }
+        let mut bytes = sealed.0.clone();
+        let (nonce, in_out) = bytes.split_at_mut(12);
+        let plain = open_in_place(&self.opening_key, nonce, TOKEN_AD, 0, in_out)
+            .map_err(|_| ErrorKind::InvalidConnectToken("Not sealed with our key".to_owned()))?;
+        let token: ConnectToken = deserialize(plain)?;
+        if token.user_data.len() > MAX_TOKEN_USER_DATA {
+            return Err(ErrorKind::InvalidConnectToken("Too much user data".to_owned()).into());
+        }
+        Ok(token)
+    }
+
+    /// Open a token and check that it is for `server_id` and has not expired
+    pub fn validate(&self, sealed: &SealedConnectToken, server_id: u64) -> Result<ConnectToken>
+    {
+        let token = self.open(sealed)?;
+        if token.server_id != server_id {
+            return Err(ErrorKind::InvalidConnectToken(
+                format!("Issued for server {}", token.server_id)).into());
+        }
+        if token.expires <= unix_time() {
+            return Err(ErrorKind::InvalidConnectToken("Expired".to_owned()).into());
+        }
+        Ok(token)
+    }
+}
+
+#[test]
+fn test() {
+    use ring::rand::SystemRandom;
+
+    let rng = SystemRandom::new();
+    let key = ConnectTokenKey::new(&[7; 32]).unwrap();
+    let token = ConnectToken {
+        player_id: 1234,
+        expires: unix_time() + 60,
+        server_id: 9,
+        user_data: b"zone=harbour".to_vec(),
+    };
+
+    let sealed = key.seal(&token, &rng).unwrap();
+    assert_eq!(key.validate(&sealed, 9).unwrap(), token);
+
+    // Wrong server
+    assert!(key.validate(&sealed, 10).is_err());
+
+    // Wrong key
+    let other_key = ConnectTokenKey::new(&[8; 32]).unwrap();
+    assert!(other_key.validate(&sealed, 9).is_err());
+
+    // Tampered
+    let mut tampered = sealed.clone();
+    let last = tampered.0.len() - 1;
+    tampered.0[last] ^= 1;
+    assert!(key.validate(&tampered, 9).is_err());
+
+    // Expired
+    let mut expired = token.clone();
+    expired.expires = unix_time() - 1;
+    assert!(key.validate(&key.seal(&expired, &rng).unwrap(), 9).is_err());
+}